@@ -9,6 +9,7 @@ use Bodys::*;
 
 use bevy::math::primitives;
 
+#[derive(Clone, Copy)]
 pub enum Bodys {
     Circle(f32),
     Rec(f32, f32),
@@ -58,6 +59,7 @@ impl Bodys {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct AABB {
     pub max: Vec2,
     pub min: Vec2,
@@ -69,14 +71,101 @@ impl AABB {
             min: Vec2::new(minx, miny),
         }
     }
+
+    pub fn union(&self, other: &AABB) -> AABB {
+        AABB {
+            max: self.max.max(other.max),
+            min: self.min.min(other.min),
+        }
+    }
+
+    pub fn contains(&self, other: &AABB) -> bool {
+        self.min.x <= other.min.x
+            && self.min.y <= other.min.y
+            && other.max.x <= self.max.x
+            && other.max.y <= self.max.y
+    }
+
+    pub fn overlaps(&self, other: &AABB) -> bool {
+        self.min.x <= other.max.x
+            && other.min.x <= self.max.x
+            && self.min.y <= other.max.y
+            && other.min.y <= self.max.y
+    }
+
+    pub fn perimeter(&self) -> f32 {
+        let size = self.max - self.min;
+        2. * (size.x + size.y)
+    }
+
+    // Distance along `direction` at which the ray enters the box, if it does so before `max_toi`.
+    pub fn ray(&self, origin: Vec2, direction: Vec2, max_toi: f32) -> Option<f32> {
+        let inv = direction.recip();
+        let t1 = (self.min - origin) * inv;
+        let t2 = (self.max - origin) * inv;
+        let tmin = t1.min(t2).max_element().max(0.);
+        let tmax = t1.max(t2).min_element().min(max_toi);
+        if tmin <= tmax {
+            Some(tmin)
+        } else {
+            None
+        }
+    }
+
+    // Grows the box by `margin` on every side and stretches it along `displacement`.
+    pub fn fatten(&self, margin: f32, displacement: Vec2) -> AABB {
+        AABB {
+            max: self.max + Vec2::splat(margin) + displacement.max(Vec2::ZERO),
+            min: self.min - Vec2::splat(margin) + displacement.min(Vec2::ZERO),
+        }
+    }
+}
+
+// Degrees of freedom a body is not allowed to move along.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Locks {
+    pub x: bool,
+    pub y: bool,
+    pub rotation: bool,
+}
+
+impl Locks {
+    // 0 on the locked axes and 1 on the free ones.
+    pub fn linear_mask(&self) -> Vec2 {
+        Vec2::new(if self.x { 0. } else { 1. }, if self.y { 0. } else { 1. })
+    }
+}
+
+// How a body takes part in the simulation. Static bodies never move, kinematic
+// bodies only follow their velocity and push dynamic bodies as if their mass was
+// infinite, dynamic bodies react to forces and contacts.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum RigidBody {
+    #[default]
+    Dynamic,
+    Static,
+    Kinematic,
+}
+
+impl RigidBody {
+    pub fn is_dynamic(&self) -> bool {
+        *self == RigidBody::Dynamic
+    }
+
+    pub fn is_static(&self) -> bool {
+        *self == RigidBody::Static
+    }
+
+    pub fn is_kinematic(&self) -> bool {
+        *self == RigidBody::Kinematic
+    }
 }
 
 pub fn create_shape(
     meshes: &mut ResMut<Assets<Mesh>>,
     shape: Bodys,
     pos: Vec2,
-    movil: bool,
-    stat: bool,
+    body: RigidBody,
 ) -> (Shape, Mesh2dHandle) {
     let (mut shape, mesh) = match shape {
         Circle(r) => {
@@ -87,10 +176,8 @@ pub fn create_shape(
                     area: cir.area(),
                     kind: cir,
                     pos,
-                    movil,
+                    body,
                     mass,
-                    is_static: stat,
-                    inv_mass: if stat { 0. } else { 1. / mass },
                     ..default()
                 },
                 Mesh2dHandle(meshes.add(primitives::Circle::new(r))),
@@ -105,10 +192,8 @@ pub fn create_shape(
                     vertices: rec.vertices(),
                     kind: rec,
                     pos,
-                    movil,
+                    body,
                     mass,
-                    is_static: stat,
-                    inv_mass: if stat { 0. } else { 1. / mass },
                     ..default()
                 },
                 Mesh2dHandle(meshes.add(Rectangle::new(w, h))),
@@ -125,10 +210,8 @@ pub fn create_shape(
                     vertices,
                     kind: poly,
                     pos,
-                    movil,
+                    body,
                     mass,
-                    is_static: stat,
-                    inv_mass: if stat { 0. } else { 1. / mass },
                     w,
                     h,
                     ..default()
@@ -137,9 +220,9 @@ pub fn create_shape(
             )
         }
     };
-    shape.aabb = shape.get_aabb();
+    shape.update_pose();
     shape.inertia = shape.get_inertia();
-    shape.inv_inertia = if stat { 0. } else { 1. / shape.inertia };
+    shape.update_inverse_mass();
     shape.restitution = 0.05;
     (shape, mesh)
 }
@@ -169,11 +252,9 @@ pub fn create_body(
     shape: Bodys,
     pos: Vec2,
     color: Color,
-    movil: bool,
-    stat: bool,
+    body: RigidBody,
 ) {
-    let (mut shape, mesh) = create_shape(meshes, shape, pos, movil, stat);
-    shape.ac = Vec2::new(0., -196.);
+    let (shape, mesh) = create_shape(meshes, shape, pos, body);
 
     spawn_shape(commands, color, mesh, materials, shape);
 }
@@ -184,18 +265,32 @@ pub struct Shape {
     pub kind: Bodys,
     pub pos: Vec2,
     pub vel: Vec2,
-    pub ac: Vec2,
+    // Absolute angle in radians.
     pub rot: f32,
     pub rot_vel: f32,
     pub mass: f32,
     pub restitution: f32,
     pub area: f32,
-    pub is_static: bool,
+    // Change it with `set_body` so the inverse masses follow.
+    pub body: RigidBody,
     pub vec: bool,
-    pub movil: bool,
+    // Vertices in the body frame, they never change after creation.
     pub vertices: Vec<Vec2>,
+    // `vertices` moved to the current pose, refreshed by `update_pose`.
+    pub world_vertices: Vec<Vec2>,
     pub force: Vec2,
+    pub torque: f32,
+    pub gravity_scale: f32,
+    // Per body damping, `None` falls back to the global `Damping` resource.
+    pub linear_damping: Option<f32>,
+    pub angular_damping: Option<f32>,
+    pub max_linear_speed: Option<f32>,
+    pub max_angular_speed: Option<f32>,
+    pub locks: Locks,
     pub inv_mass: f32,
+    // Offset of the center of mass from `pos` in the body frame. Bodies rotate
+    // around it and `vel` is its velocity.
+    pub center_of_mass: Vec2,
     pub aabb: AABB,
     pub inertia: f32,
     pub inv_inertia: f32,
@@ -203,6 +298,7 @@ pub struct Shape {
     pub dinaminc_friction: f32,
     pub h: f32,
     pub w: f32,
+    pub sleep_time: f32,
 }
 
 impl std::default::Default for Shape {
@@ -211,18 +307,25 @@ impl std::default::Default for Shape {
             kind: Circle(20.),
             pos: Vec2::default(),
             vel: Vec2::default(),
-            ac: Vec2::default(),
             rot: 0.,
             rot_vel: 0.,
             mass: 1.,
             restitution: 1.,
             area: 0.,
-            is_static: false,
+            body: RigidBody::Dynamic,
             vec: false,
-            movil: true,
             vertices: vec![],
+            world_vertices: vec![],
             force: Vec2::default(),
+            torque: 0.,
+            gravity_scale: 1.,
+            linear_damping: None,
+            angular_damping: None,
+            max_linear_speed: None,
+            max_angular_speed: None,
+            locks: Locks::default(),
             inv_mass: 0.,
+            center_of_mass: Vec2::ZERO,
             aabb: AABB::new(0., 0., 0., 0.),
             inertia: 0.,
             inv_inertia: 0.,
@@ -230,11 +333,25 @@ impl std::default::Default for Shape {
             dinaminc_friction: 0.4,
             h: 0.,
             w: 0.,
+            sleep_time: 0.,
         }
     }
 }
 
 impl Shape {
+    // Recomputes the world vertices and the AABB from `pos` and `rot`.
+    pub fn update_pose(&mut self) {
+        let (sin, cos) = self.rot.sin_cos();
+        let pos = self.pos;
+        self.world_vertices.clear();
+        self.world_vertices.extend(self.vertices.iter().map(|i| {
+            let rx = cos * i.x - sin * i.y;
+            let ry = sin * i.x + cos * i.y;
+            Vec2::new(rx, ry) + pos
+        }));
+        self.aabb = self.get_aabb();
+    }
+
     pub fn get_aabb(&self) -> AABB {
         match self.kind {
             Circle(r) => {
@@ -249,8 +366,7 @@ impl Shape {
                 let mut maxx = f32::MIN;
                 let mut miny = f32::MAX;
                 let mut maxy = f32::MIN;
-                for i in &self.vertices {
-                    let i = *i + self.pos;
+                for i in &self.world_vertices {
                     maxx = maxx.max(i.x);
                     maxy = maxy.max(i.y);
                     minx = minx.min(i.x);
@@ -272,7 +388,177 @@ impl Shape {
             }
         }
     }
+
+    // Point given in the body frame, relative to `pos`, moved to world space.
+    pub fn world_point(&self, local: Vec2) -> Vec2 {
+        self.pos + Vec2::from_angle(self.rot).rotate(local)
+    }
+
+    pub fn world_center_of_mass(&self) -> Vec2 {
+        self.world_point(self.center_of_mass)
+    }
+
+    // Moves `pos` so the center of mass ends at `center` with the current rotation.
+    pub fn place_center_of_mass(&mut self, center: Vec2) {
+        self.pos = center - Vec2::from_angle(self.rot).rotate(self.center_of_mass);
+    }
+
+    // Overrides the mass derived from the area, the inertia keeps the same distribution.
+    // Without a previous mass to scale, it is derived from the shape again. Masses
+    // that aren't positive are ignored, make the body static or kinematic instead.
+    pub fn set_mass(&mut self, mass: f32) {
+        if !(mass > 0. && mass.is_finite()) {
+            warn!("ignoring mass {mass}, it must be positive");
+            return;
+        }
+        if self.mass > 0. {
+            self.inertia *= mass / self.mass;
+            self.mass = mass;
+        } else {
+            self.mass = mass;
+            let shift = self.center_of_mass.length_squared();
+            self.inertia = (self.get_inertia() - mass * shift).max(0.);
+        }
+        self.update_inverse_mass();
+    }
+
+    // Inertia around the center of mass.
+    pub fn set_inertia(&mut self, inertia: f32) {
+        self.inertia = inertia;
+        self.update_inverse_mass();
+    }
+
+    // `center` is in the body frame. The inertia around the origin of the shape
+    // is kept, the parallel axis theorem gives the one around the new center.
+    // Call `set_inertia` afterwards to give it directly.
+    pub fn set_center_of_mass(&mut self, center: Vec2) {
+        let shift = self.center_of_mass.length_squared() - center.length_squared();
+        self.inertia = (self.inertia + self.mass * shift).max(0.);
+        self.center_of_mass = center;
+        self.update_inverse_mass();
+    }
+
+    // Switches the type of the body at runtime. A sleeping body becoming static or
+    // kinematic is woken up by `wake_bodies`.
+    pub fn set_body(&mut self, body: RigidBody) {
+        self.body = body;
+        self.sleep_time = 0.;
+        self.update_inverse_mass();
+    }
+
+    // Non dynamic bodies behave as if their mass was infinite.
+    pub fn update_inverse_mass(&mut self) {
+        let dynamic = self.body.is_dynamic();
+        self.inv_mass = if dynamic && self.mass > 0. {
+            1. / self.mass
+        } else {
+            0.
+        };
+        self.inv_inertia = if dynamic && self.inertia > 0. {
+            1. / self.inertia
+        } else {
+            0.
+        };
+    }
+
+    // Inverse mass per axis, zero along the locked ones.
+    pub fn effective_inv_mass(&self) -> Vec2 {
+        self.inv_mass * self.locks.linear_mask()
+    }
+
+    pub fn effective_inv_inertia(&self) -> f32 {
+        if self.locks.rotation {
+            0.
+        } else {
+            self.inv_inertia
+        }
+    }
+
+    // Clamps the velocities to the body's speed limits and zeroes them on locked axes.
+    pub fn limit_velocity(&mut self) {
+        self.vel *= self.locks.linear_mask();
+        if self.locks.rotation {
+            self.rot_vel = 0.;
+        }
+        if let Some(max) = self.max_linear_speed {
+            self.vel = self.vel.clamp_length_max(max);
+        }
+        if let Some(max) = self.max_angular_speed {
+            self.rot_vel = self.rot_vel.clamp(-max, max);
+        }
+    }
+
+    // Forces and torques are accumulated, every substep of the next fixed tick
+    // integrates them and they are cleared after it.
+    pub fn apply_force(&mut self, force: Vec2) {
+        self.force += force;
+    }
+
+    // `point` is in world space, pushing off-center also adds torque.
+    pub fn apply_force_at_point(&mut self, force: Vec2, point: Vec2) {
+        self.force += force;
+        self.torque += (point - self.world_center_of_mass()).perp_dot(force);
+    }
+
+    pub fn apply_torque(&mut self, torque: f32) {
+        self.torque += torque;
+    }
+
+    // Impulses change the velocity right away.
+    pub fn apply_impulse(&mut self, impulse: Vec2) {
+        self.vel += impulse * self.effective_inv_mass();
+    }
+
+    pub fn apply_impulse_at_point(&mut self, impulse: Vec2, point: Vec2) {
+        self.vel += impulse * self.effective_inv_mass();
+        let r = point - self.world_center_of_mass();
+        self.rot_vel += r.perp_dot(impulse) * self.effective_inv_inertia();
+    }
+
+    pub fn apply_angular_impulse(&mut self, impulse: f32) {
+        self.rot_vel += impulse * self.effective_inv_inertia();
+    }
 }
 
 #[derive(Default, Reflect, GizmoConfigGroup)]
 struct Vecs {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    fn shape(kind: Bodys) -> Shape {
+        let mut world = World::new();
+        world.init_resource::<Assets<Mesh>>();
+        world.run_system_once(move |mut meshes: ResMut<Assets<Mesh>>| {
+            create_shape(&mut meshes, kind, Vec2::ZERO, RigidBody::Dynamic).0
+        })
+    }
+
+    #[test]
+    fn set_mass_ignores_non_positive_masses() {
+        let mut shape = shape(Rec(20., 20.));
+        let (mass, inv_mass) = (shape.mass, shape.inv_mass);
+        for mass in [0., -1., f32::NAN] {
+            shape.set_mass(mass);
+        }
+        assert_eq!((shape.mass, shape.inv_mass), (mass, inv_mass));
+
+        shape.set_mass(mass * 2.);
+        assert_eq!(shape.inv_mass, inv_mass / 2.);
+    }
+
+    #[test]
+    fn center_of_mass_moves_the_inertia() {
+        let mut shape = shape(Circle(20.));
+        let (mass, inertia) = (shape.mass, shape.inertia);
+        let center = Vec2::new(0., -12.);
+        shape.set_center_of_mass(center);
+        let expected = inertia - mass * center.length_squared();
+        assert!((shape.inertia - expected).abs() < expected * 1e-4);
+
+        shape.set_center_of_mass(Vec2::ZERO);
+        assert!((shape.inertia - inertia).abs() < inertia * 1e-4);
+    }
+}
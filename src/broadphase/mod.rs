@@ -0,0 +1,834 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::body::{Shape, AABB};
+use super::determinism::Determinism;
+use super::integrator::SubstepTime;
+
+const NULL: usize = usize::MAX;
+const AABB_MARGIN: f32 = 5.;
+const DISPLACEMENT_MULTIPLIER: f32 = 4.;
+
+#[derive(Clone, Copy)]
+struct Node {
+    aabb: AABB,
+    parent: usize,
+    left: usize,
+    right: usize,
+    height: i32,
+    entity: Option<Entity>,
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.left == NULL
+    }
+}
+
+// Bounding volume hierarchy where every leaf stores a fat AABB, so a body only
+// has to be reinserted once its tight AABB leaves the fat one.
+pub struct DynamicTree {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    root: usize,
+    proxies: HashMap<Entity, usize>,
+}
+
+impl std::default::Default for DynamicTree {
+    fn default() -> Self {
+        Self {
+            nodes: vec![],
+            free: vec![],
+            root: NULL,
+            proxies: HashMap::default(),
+        }
+    }
+}
+
+impl DynamicTree {
+    pub fn len(&self) -> usize {
+        self.proxies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.proxies.contains_key(&entity)
+    }
+
+    pub fn fat_aabb(&self, entity: Entity) -> Option<AABB> {
+        self.proxies.get(&entity).map(|i| self.nodes[*i].aabb)
+    }
+
+    pub fn insert(&mut self, entity: Entity, aabb: AABB, displacement: Vec2) {
+        if self.contains(entity) {
+            self.remove(entity);
+        }
+        let fat = aabb.fatten(AABB_MARGIN, displacement * DISPLACEMENT_MULTIPLIER);
+        let leaf = self.allocate(fat, Some(entity));
+        self.insert_leaf(leaf);
+        self.proxies.insert(entity, leaf);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        if let Some(leaf) = self.proxies.remove(&entity) {
+            self.remove_leaf(leaf);
+            self.release(leaf);
+        }
+    }
+
+    // Returns true when the body left its fat AABB and had to be reinserted.
+    pub fn update(&mut self, entity: Entity, aabb: AABB, displacement: Vec2) -> bool {
+        let Some(&leaf) = self.proxies.get(&entity) else {
+            self.insert(entity, aabb, displacement);
+            return true;
+        };
+        let fat = self.nodes[leaf].aabb;
+        if fat.contains(&aabb) {
+            let huge = aabb.fatten(
+                4. * AABB_MARGIN,
+                displacement * DISPLACEMENT_MULTIPLIER * 4.,
+            );
+            if huge.contains(&fat) {
+                return false;
+            }
+        }
+        self.remove_leaf(leaf);
+        self.nodes[leaf].aabb = aabb.fatten(AABB_MARGIN, displacement * DISPLACEMENT_MULTIPLIER);
+        self.insert_leaf(leaf);
+        true
+    }
+
+    pub fn query(&self, aabb: &AABB, mut callback: impl FnMut(Entity)) {
+        self.query_nodes(aabb, |i| {
+            if let Some(entity) = self.nodes[i].entity {
+                callback(entity)
+            }
+        });
+    }
+
+    pub fn ray(
+        &self,
+        origin: Vec2,
+        direction: Vec2,
+        max_toi: f32,
+        mut callback: impl FnMut(Entity, f32),
+    ) {
+        if self.root == NULL {
+            return;
+        }
+        let mut stack = vec![self.root];
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            let Some(toi) = node.aabb.ray(origin, direction, max_toi) else {
+                continue;
+            };
+            match node.entity {
+                Some(entity) => callback(entity, toi),
+                None => {
+                    stack.push(node.left);
+                    stack.push(node.right);
+                }
+            }
+        }
+    }
+
+    // Every pair of leaves inside this tree whose fat AABBs overlap.
+    pub fn pairs(&self) -> Vec<(Entity, Entity)> {
+        let mut pairs = vec![];
+        for (i, node) in self.nodes.iter().enumerate() {
+            let Some(a) = node.entity else {
+                continue;
+            };
+            self.query_nodes(&node.aabb, |j| {
+                if j > i {
+                    if let Some(b) = self.nodes[j].entity {
+                        pairs.push((a, b));
+                    }
+                }
+            });
+        }
+        pairs
+    }
+
+    // Every pair of leaves between this tree and `other` whose fat AABBs overlap.
+    pub fn pairs_with(&self, other: &DynamicTree) -> Vec<(Entity, Entity)> {
+        let mut pairs = vec![];
+        for node in &self.nodes {
+            let Some(a) = node.entity else {
+                continue;
+            };
+            other.query(&node.aabb, |b| pairs.push((a, b)));
+        }
+        pairs
+    }
+
+    fn query_nodes(&self, aabb: &AABB, mut callback: impl FnMut(usize)) {
+        if self.root == NULL {
+            return;
+        }
+        let mut stack = vec![self.root];
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            if !node.aabb.overlaps(aabb) {
+                continue;
+            }
+            if node.is_leaf() {
+                callback(i);
+            } else {
+                stack.push(node.left);
+                stack.push(node.right);
+            }
+        }
+    }
+
+    fn allocate(&mut self, aabb: AABB, entity: Option<Entity>) -> usize {
+        let node = Node {
+            aabb,
+            parent: NULL,
+            left: NULL,
+            right: NULL,
+            height: 0,
+            entity,
+        };
+        match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = node;
+                i
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn release(&mut self, i: usize) {
+        self.nodes[i].entity = None;
+        self.nodes[i].height = -1;
+        self.free.push(i);
+    }
+
+    fn insert_leaf(&mut self, leaf: usize) {
+        if self.root == NULL {
+            self.root = leaf;
+            self.nodes[leaf].parent = NULL;
+            return;
+        }
+
+        let leaf_aabb = self.nodes[leaf].aabb;
+        let mut index = self.root;
+        while !self.nodes[index].is_leaf() {
+            let node = self.nodes[index];
+            let area = node.aabb.perimeter();
+            let combined = node.aabb.union(&leaf_aabb).perimeter();
+
+            let cost = 2. * combined;
+            let inheritance = 2. * (combined - area);
+
+            let child_cost = |child: &Node| {
+                let union = leaf_aabb.union(&child.aabb).perimeter();
+                if child.is_leaf() {
+                    union + inheritance
+                } else {
+                    union - child.aabb.perimeter() + inheritance
+                }
+            };
+            let cost_left = child_cost(&self.nodes[node.left]);
+            let cost_right = child_cost(&self.nodes[node.right]);
+
+            if cost < cost_left && cost < cost_right {
+                break;
+            }
+            index = if cost_left < cost_right {
+                node.left
+            } else {
+                node.right
+            };
+        }
+
+        let sibling = index;
+        let old_parent = self.nodes[sibling].parent;
+        let new_parent = self.allocate(leaf_aabb.union(&self.nodes[sibling].aabb), None);
+        self.nodes[new_parent].parent = old_parent;
+        self.nodes[new_parent].height = self.nodes[sibling].height + 1;
+        self.nodes[new_parent].left = sibling;
+        self.nodes[new_parent].right = leaf;
+        self.nodes[sibling].parent = new_parent;
+        self.nodes[leaf].parent = new_parent;
+
+        if old_parent == NULL {
+            self.root = new_parent;
+        } else {
+            self.replace_child(old_parent, sibling, new_parent);
+        }
+
+        self.refit(self.nodes[leaf].parent);
+    }
+
+    fn remove_leaf(&mut self, leaf: usize) {
+        if leaf == self.root {
+            self.root = NULL;
+            return;
+        }
+
+        let parent = self.nodes[leaf].parent;
+        let grand_parent = self.nodes[parent].parent;
+        let sibling = if self.nodes[parent].left == leaf {
+            self.nodes[parent].right
+        } else {
+            self.nodes[parent].left
+        };
+
+        if grand_parent == NULL {
+            self.root = sibling;
+            self.nodes[sibling].parent = NULL;
+            self.release(parent);
+        } else {
+            self.replace_child(grand_parent, parent, sibling);
+            self.nodes[sibling].parent = grand_parent;
+            self.release(parent);
+            self.refit(grand_parent);
+        }
+        self.nodes[leaf].parent = NULL;
+    }
+
+    fn refit(&mut self, mut index: usize) {
+        while index != NULL {
+            index = self.balance(index);
+            let left = self.nodes[index].left;
+            let right = self.nodes[index].right;
+            self.nodes[index].height = 1 + self.nodes[left].height.max(self.nodes[right].height);
+            self.nodes[index].aabb = self.nodes[left].aabb.union(&self.nodes[right].aabb);
+            index = self.nodes[index].parent;
+        }
+    }
+
+    fn replace_child(&mut self, parent: usize, old: usize, new: usize) {
+        if self.nodes[parent].left == old {
+            self.nodes[parent].left = new;
+        } else {
+            self.nodes[parent].right = new;
+        }
+    }
+
+    fn balance(&mut self, a: usize) -> usize {
+        if self.nodes[a].is_leaf() || self.nodes[a].height < 2 {
+            return a;
+        }
+        let b = self.nodes[a].left;
+        let c = self.nodes[a].right;
+        let balance = self.nodes[c].height - self.nodes[b].height;
+        if balance > 1 {
+            self.rotate(a, c, b)
+        } else if balance < -1 {
+            self.rotate(a, b, c)
+        } else {
+            a
+        }
+    }
+
+    // Promotes the child `up` of `a` to take the place of `a`.
+    fn rotate(&mut self, a: usize, up: usize, other: usize) -> usize {
+        let f = self.nodes[up].left;
+        let g = self.nodes[up].right;
+        let parent = self.nodes[a].parent;
+
+        self.nodes[up].left = a;
+        self.nodes[up].parent = parent;
+        self.nodes[a].parent = up;
+        if parent == NULL {
+            self.root = up;
+        } else {
+            self.replace_child(parent, a, up);
+        }
+
+        let (keep, give) = if self.nodes[f].height > self.nodes[g].height {
+            (f, g)
+        } else {
+            (g, f)
+        };
+        self.nodes[up].right = keep;
+        self.replace_child(a, up, give);
+        self.nodes[give].parent = a;
+
+        self.nodes[a].aabb = self.nodes[other].aabb.union(&self.nodes[give].aabb);
+        self.nodes[up].aabb = self.nodes[a].aabb.union(&self.nodes[keep].aabb);
+        self.nodes[a].height = 1 + self.nodes[other].height.max(self.nodes[give].height);
+        self.nodes[up].height = 1 + self.nodes[a].height.max(self.nodes[keep].height);
+        up
+    }
+}
+
+pub trait Broadphase: Send + Sync + 'static {
+    fn insert(&mut self, entity: Entity, aabb: AABB, is_static: bool, displacement: Vec2);
+
+    fn remove(&mut self, entity: Entity);
+
+    // Refits a body already inserted, `is_static` may differ from the last call
+    // when the type of the body changed.
+    fn update(&mut self, entity: Entity, aabb: AABB, is_static: bool, displacement: Vec2);
+
+    fn contains(&self, entity: Entity) -> bool;
+
+    // Bounds kept for `entity`, fattened when the strategy does so.
+    fn aabb(&self, entity: Entity) -> Option<AABB>;
+
+    // Candidate pairs for the narrowphase; static-static pairs are never reported.
+    fn query_pairs(&self) -> Vec<(Entity, Entity)>;
+
+    fn query_region(&self, aabb: &AABB) -> Vec<Entity>;
+
+    // Candidates hit by the ray, sorted by the distance at which it enters their bounds.
+    fn ray(&self, origin: Vec2, direction: Vec2, max_toi: f32) -> Vec<(Entity, f32)>;
+}
+
+// Broadphase used by the engine. Insert it before adding the plugin to pick a
+// different strategy, by default the `AabbTrees` are used.
+#[derive(Resource)]
+pub struct PhysicsBroadphase(pub Box<dyn Broadphase>);
+
+impl PhysicsBroadphase {
+    pub fn new(broadphase: impl Broadphase) -> Self {
+        Self(Box::new(broadphase))
+    }
+}
+
+impl std::default::Default for PhysicsBroadphase {
+    fn default() -> Self {
+        Self::new(AabbTrees::default())
+    }
+}
+
+fn sort_hits(mut hits: Vec<(Entity, f32)>) -> Vec<(Entity, f32)> {
+    hits.sort_by(|a, b| a.1.total_cmp(&b.1));
+    hits
+}
+
+// Moving bodies live in `dynamic`; static level geometry is inserted once into
+// `statics` and never refitted. Bodies changing type move between the two.
+#[derive(Default)]
+pub struct AabbTrees {
+    pub dynamic: DynamicTree,
+    pub statics: DynamicTree,
+}
+
+impl Broadphase for AabbTrees {
+    fn insert(&mut self, entity: Entity, aabb: AABB, is_static: bool, displacement: Vec2) {
+        self.remove(entity);
+        if is_static {
+            self.statics.insert(entity, aabb, Vec2::ZERO);
+        } else {
+            self.dynamic.insert(entity, aabb, displacement);
+        }
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        self.dynamic.remove(entity);
+        self.statics.remove(entity);
+    }
+
+    fn update(&mut self, entity: Entity, aabb: AABB, is_static: bool, displacement: Vec2) {
+        if is_static {
+            if self.dynamic.contains(entity) {
+                self.dynamic.remove(entity);
+                self.statics.insert(entity, aabb, Vec2::ZERO);
+            }
+        } else if self.statics.contains(entity) {
+            self.statics.remove(entity);
+            self.dynamic.insert(entity, aabb, displacement);
+        } else {
+            self.dynamic.update(entity, aabb, displacement);
+        }
+    }
+
+    fn contains(&self, entity: Entity) -> bool {
+        self.dynamic.contains(entity) || self.statics.contains(entity)
+    }
+
+    fn aabb(&self, entity: Entity) -> Option<AABB> {
+        self.dynamic
+            .fat_aabb(entity)
+            .or_else(|| self.statics.fat_aabb(entity))
+    }
+
+    fn query_pairs(&self) -> Vec<(Entity, Entity)> {
+        let mut pairs = self.dynamic.pairs();
+        pairs.extend(self.dynamic.pairs_with(&self.statics));
+        pairs
+    }
+
+    fn query_region(&self, aabb: &AABB) -> Vec<Entity> {
+        let mut result = vec![];
+        self.dynamic.query(aabb, |e| result.push(e));
+        self.statics.query(aabb, |e| result.push(e));
+        result
+    }
+
+    fn ray(&self, origin: Vec2, direction: Vec2, max_toi: f32) -> Vec<(Entity, f32)> {
+        let mut hits = vec![];
+        self.dynamic
+            .ray(origin, direction, max_toi, |e, toi| hits.push((e, toi)));
+        self.statics
+            .ray(origin, direction, max_toi, |e, toi| hits.push((e, toi)));
+        sort_hits(hits)
+    }
+}
+
+struct Proxy {
+    entity: Entity,
+    aabb: AABB,
+    is_static: bool,
+}
+
+// Flat list of proxies shared by the brute force and sweep and prune strategies.
+#[derive(Default)]
+struct ProxyList {
+    proxies: Vec<Proxy>,
+    index: HashMap<Entity, usize>,
+}
+
+impl ProxyList {
+    fn insert(&mut self, entity: Entity, aabb: AABB, is_static: bool) {
+        let proxy = Proxy {
+            entity,
+            aabb,
+            is_static,
+        };
+        match self.index.get(&entity) {
+            Some(&i) => self.proxies[i] = proxy,
+            None => {
+                self.index.insert(entity, self.proxies.len());
+                self.proxies.push(proxy);
+            }
+        }
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        if let Some(i) = self.index.remove(&entity) {
+            self.proxies.swap_remove(i);
+            if let Some(moved) = self.proxies.get(i) {
+                self.index.insert(moved.entity, i);
+            }
+        }
+    }
+
+    fn update(&mut self, entity: Entity, aabb: AABB, is_static: bool) {
+        if let Some(&i) = self.index.get(&entity) {
+            self.proxies[i].aabb = aabb;
+            self.proxies[i].is_static = is_static;
+        }
+    }
+
+    fn aabb(&self, entity: Entity) -> Option<AABB> {
+        self.index.get(&entity).map(|&i| self.proxies[i].aabb)
+    }
+
+    fn query_region(&self, aabb: &AABB) -> Vec<Entity> {
+        self.proxies
+            .iter()
+            .filter(|p| p.aabb.overlaps(aabb))
+            .map(|p| p.entity)
+            .collect()
+    }
+
+    fn ray(&self, origin: Vec2, direction: Vec2, max_toi: f32) -> Vec<(Entity, f32)> {
+        let hits = self
+            .proxies
+            .iter()
+            .filter_map(|p| {
+                p.aabb
+                    .ray(origin, direction, max_toi)
+                    .map(|t| (p.entity, t))
+            })
+            .collect();
+        sort_hits(hits)
+    }
+}
+
+// Tests every pair of bodies, the behaviour the engine originally had.
+#[derive(Default)]
+pub struct BruteForce {
+    list: ProxyList,
+}
+
+impl Broadphase for BruteForce {
+    fn insert(&mut self, entity: Entity, aabb: AABB, is_static: bool, _displacement: Vec2) {
+        self.list.insert(entity, aabb, is_static);
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        self.list.remove(entity);
+    }
+
+    fn update(&mut self, entity: Entity, aabb: AABB, is_static: bool, _displacement: Vec2) {
+        self.list.update(entity, aabb, is_static);
+    }
+
+    fn contains(&self, entity: Entity) -> bool {
+        self.list.index.contains_key(&entity)
+    }
+
+    fn aabb(&self, entity: Entity) -> Option<AABB> {
+        self.list.aabb(entity)
+    }
+
+    fn query_pairs(&self) -> Vec<(Entity, Entity)> {
+        let proxies = &self.list.proxies;
+        let mut pairs = vec![];
+        for (i, a) in proxies.iter().enumerate() {
+            for b in &proxies[i + 1..] {
+                if !(a.is_static && b.is_static) {
+                    pairs.push((a.entity, b.entity));
+                }
+            }
+        }
+        pairs
+    }
+
+    fn query_region(&self, aabb: &AABB) -> Vec<Entity> {
+        self.list.query_region(aabb)
+    }
+
+    fn ray(&self, origin: Vec2, direction: Vec2, max_toi: f32) -> Vec<(Entity, f32)> {
+        self.list.ray(origin, direction, max_toi)
+    }
+}
+
+// Sorts the bodies along the x axis every step and only pairs the ones whose
+// intervals overlap.
+#[derive(Default)]
+pub struct SweepAndPrune {
+    list: ProxyList,
+}
+
+impl Broadphase for SweepAndPrune {
+    fn insert(&mut self, entity: Entity, aabb: AABB, is_static: bool, _displacement: Vec2) {
+        self.list.insert(entity, aabb, is_static);
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        self.list.remove(entity);
+    }
+
+    fn update(&mut self, entity: Entity, aabb: AABB, is_static: bool, _displacement: Vec2) {
+        self.list.update(entity, aabb, is_static);
+    }
+
+    fn contains(&self, entity: Entity) -> bool {
+        self.list.index.contains_key(&entity)
+    }
+
+    fn aabb(&self, entity: Entity) -> Option<AABB> {
+        self.list.aabb(entity)
+    }
+
+    fn query_pairs(&self) -> Vec<(Entity, Entity)> {
+        let mut sorted: Vec<&Proxy> = self.list.proxies.iter().collect();
+        sorted.sort_by(|a, b| a.aabb.min.x.total_cmp(&b.aabb.min.x));
+
+        let mut pairs = vec![];
+        for (i, a) in sorted.iter().enumerate() {
+            for b in &sorted[i + 1..] {
+                if b.aabb.min.x > a.aabb.max.x {
+                    break;
+                }
+                if !(a.is_static && b.is_static) && a.aabb.overlaps(&b.aabb) {
+                    pairs.push((a.entity, b.entity));
+                }
+            }
+        }
+        pairs
+    }
+
+    fn query_region(&self, aabb: &AABB) -> Vec<Entity> {
+        self.list.query_region(aabb)
+    }
+
+    fn ray(&self, origin: Vec2, direction: Vec2, max_toi: f32) -> Vec<(Entity, f32)> {
+        self.list.ray(origin, direction, max_toi)
+    }
+}
+
+pub fn update_broadphase(
+    mut broadphase: ResMut<PhysicsBroadphase>,
+    query: Query<(Entity, &Shape)>,
+    mut removed: RemovedComponents<Shape>,
+    step: Res<SubstepTime>,
+    determinism: Res<Determinism>,
+) {
+    let time = step.0;
+    let broadphase = &mut broadphase.0;
+
+    for entity in removed.read() {
+        broadphase.remove(entity);
+    }
+    // The shape of the trees depends on the insertion order.
+    let mut bodies: Vec<_> = query.iter().collect();
+    determinism.order(&mut bodies, |(entity, _)| *entity);
+    for (entity, shape) in bodies {
+        let aabb = shape.get_aabb();
+        let displacement = shape.vel * time;
+        let is_static = shape.body.is_static();
+        if broadphase.contains(entity) {
+            broadphase.update(entity, aabb, is_static, displacement);
+        } else {
+            broadphase.insert(entity, aabb, is_static, displacement);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_aabb(rng: &mut StdRng) -> AABB {
+        let min = Vec2::new(rng.gen_range(-500.0..500.), rng.gen_range(-500.0..500.));
+        let size = Vec2::new(rng.gen_range(1.0..60.), rng.gen_range(1.0..60.));
+        AABB {
+            min,
+            max: min + size,
+        }
+    }
+
+    // Walks the tree from the root checking the links, heights and bounds of
+    // every node, and that the proxies point at the leaves of their entities.
+    fn check(tree: &DynamicTree) {
+        let mut leaves = 0;
+        if tree.root != NULL {
+            assert_eq!(tree.nodes[tree.root].parent, NULL);
+            let mut stack = vec![tree.root];
+            while let Some(i) = stack.pop() {
+                let node = &tree.nodes[i];
+                if node.is_leaf() {
+                    assert_eq!(node.height, 0);
+                    let entity = node.entity.expect("leaf without an entity");
+                    assert_eq!(tree.proxies[&entity], i);
+                    leaves += 1;
+                    continue;
+                }
+                assert!(node.entity.is_none());
+                let (left, right) = (&tree.nodes[node.left], &tree.nodes[node.right]);
+                assert_eq!(left.parent, i);
+                assert_eq!(right.parent, i);
+                assert_eq!(node.height, 1 + left.height.max(right.height));
+                assert!(node.aabb.contains(&left.aabb));
+                assert!(node.aabb.contains(&right.aabb));
+                stack.push(node.left);
+                stack.push(node.right);
+            }
+        }
+        assert_eq!(leaves, tree.len());
+    }
+
+    #[test]
+    fn insert_remove_and_update_keep_the_tree_valid() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut tree = DynamicTree::default();
+        let entities: Vec<_> = (0..200).map(Entity::from_raw).collect();
+
+        for &entity in &entities {
+            tree.insert(entity, random_aabb(&mut rng), Vec2::ZERO);
+            check(&tree);
+        }
+        for &entity in &entities {
+            let aabb = random_aabb(&mut rng);
+            let displacement = Vec2::new(rng.gen_range(-5.0..5.), rng.gen_range(-5.0..5.));
+            tree.update(entity, aabb, displacement);
+            assert!(tree.fat_aabb(entity).unwrap().contains(&aabb));
+            check(&tree);
+        }
+        for &entity in entities.iter().step_by(2) {
+            tree.remove(entity);
+            assert!(!tree.contains(entity));
+            check(&tree);
+        }
+        assert_eq!(tree.len(), entities.len() / 2);
+
+        for &entity in entities.iter().skip(1).step_by(2) {
+            tree.remove(entity);
+        }
+        check(&tree);
+        assert!(tree.is_empty());
+        assert_eq!(tree.root, NULL);
+    }
+
+    #[test]
+    fn update_inside_the_fat_aabb_keeps_the_leaf() {
+        let mut tree = DynamicTree::default();
+        let entity = Entity::from_raw(0);
+        let aabb = AABB {
+            min: Vec2::ZERO,
+            max: Vec2::splat(10.),
+        };
+        tree.insert(entity, aabb, Vec2::ZERO);
+        let moved = AABB {
+            min: aabb.min + Vec2::splat(1.),
+            max: aabb.max + Vec2::splat(1.),
+        };
+        assert!(!tree.update(entity, moved, Vec2::ZERO));
+        let far = AABB {
+            min: aabb.min + Vec2::splat(100.),
+            max: aabb.max + Vec2::splat(100.),
+        };
+        assert!(tree.update(entity, far, Vec2::ZERO));
+        assert!(tree.fat_aabb(entity).unwrap().contains(&far));
+    }
+
+    #[test]
+    fn pairs_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut tree = DynamicTree::default();
+        let mut brute = BruteForce::default();
+        for i in 0..300 {
+            let entity = Entity::from_raw(i);
+            tree.insert(entity, random_aabb(&mut rng), Vec2::ZERO);
+            brute.insert(entity, tree.fat_aabb(entity).unwrap(), false, Vec2::ZERO);
+        }
+
+        let sorted = |pairs: Vec<(Entity, Entity)>| {
+            let mut pairs: Vec<_> = pairs
+                .into_iter()
+                .map(|(a, b)| (a.min(b), a.max(b)))
+                .collect();
+            pairs.sort();
+            pairs
+        };
+        let expected: Vec<_> = brute
+            .query_pairs()
+            .into_iter()
+            .filter(|&(a, b)| {
+                let (a, b) = (tree.fat_aabb(a).unwrap(), tree.fat_aabb(b).unwrap());
+                a.overlaps(&b)
+            })
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(sorted(tree.pairs()), sorted(expected));
+    }
+
+    #[test]
+    fn changing_body_type_moves_the_proxy() {
+        let (floor, body) = (Entity::from_raw(0), Entity::from_raw(1));
+        let aabb = |y: f32| AABB {
+            min: Vec2::new(0., y),
+            max: Vec2::new(10., y + 10.),
+        };
+        let broadphases: [Box<dyn Broadphase>; 3] = [
+            Box::new(AabbTrees::default()),
+            Box::new(BruteForce::default()),
+            Box::new(SweepAndPrune::default()),
+        ];
+        for mut broadphase in broadphases {
+            broadphase.insert(floor, aabb(0.), true, Vec2::ZERO);
+            broadphase.insert(body, aabb(100.), true, Vec2::ZERO);
+            assert!(broadphase.query_pairs().is_empty());
+
+            broadphase.update(body, aabb(5.), false, Vec2::ZERO);
+            assert_eq!(broadphase.query_pairs().len(), 1);
+            broadphase.update(body, aabb(5.), true, Vec2::ZERO);
+            assert!(broadphase.query_pairs().is_empty());
+        }
+    }
+}
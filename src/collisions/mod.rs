@@ -1,39 +1,94 @@
 use super::body::Bodys::*;
 use super::body::Shape;
-use bevy::prelude::*;
+use bevy::{prelude::*, tasks::ComputeTaskPool};
+
+pub struct Contact {
+    pub a: Entity,
+    pub b: Entity,
+    pub normal: Vec2,
+    pub depth: f32,
+    pub points: [Vec2; 2],
+    // Penetration at each point, `depth` is the deepest of them.
+    pub depths: [f32; 2],
+    pub count: usize,
+}
 
-fn prox_equals_f32(a: f32, b: f32) -> bool {
-    (a > b && a - b < 0.1) || (a < b && a - b > -0.1)
+impl Contact {
+    pub fn points(&self) -> &[Vec2] {
+        &self.points[..self.count]
+    }
 }
 
-fn prox_equals_vec2(a: Vec2, b: Vec2) -> bool {
-    prox_equals_f32(a.x, b.x) && prox_equals_f32(a.y, b.y)
+// Runs the narrowphase for every broadphase pair, spreading the pairs over the
+// compute task pool. Contacts come back in the same order as `pairs`.
+pub fn narrowphase(pairs: &[(Entity, Entity)], query: &Query<&Shape>) -> Vec<Contact> {
+    let pool = ComputeTaskPool::get();
+    let chunk_size = (pairs.len() / pool.thread_num().max(1)).max(32);
+    pool.scope(|s| {
+        for chunk in pairs.chunks(chunk_size) {
+            s.spawn(async move {
+                chunk
+                    .iter()
+                    .filter_map(|&(ea, eb)| {
+                        let [a, b] = query.get_many([ea, eb]).ok()?;
+                        a.contact(ea, b, eb)
+                    })
+                    .collect::<Vec<_>>()
+            });
+        }
+    })
+    .into_iter()
+    .flatten()
+    .collect()
 }
 
 impl Shape {
-    pub fn collision(&self, pb: Vec2, shape: &Shape, mut gizmos: &mut Gizmos) -> (Vec2, f32) {
+    pub fn contact(&self, ea: Entity, shape: &Shape, eb: Entity) -> Option<Contact> {
+        if !self.body.is_dynamic() && !shape.body.is_dynamic() {
+            return None;
+        }
+        if self.checkaabb(shape) {
+            return None;
+        }
+        let (normal, depth) = self.collision(shape.pos, shape);
+        if normal == Vec2::ZERO || depth <= 0. {
+            return None;
+        }
+        let (points, depths, count) = self.contactpoint(shape, normal, depth);
+        Some(Contact {
+            a: ea,
+            b: eb,
+            normal,
+            depth,
+            points,
+            depths,
+            count,
+        })
+    }
+
+    pub fn collision(&self, pb: Vec2, shape: &Shape) -> (Vec2, f32) {
         match (&self.kind, &shape.kind) {
             (Circle(ra), Circle(rb)) => self.cir_to_cir(*ra, *rb, pb),
             (Rec(_, _), Circle(_)) => {
-                let (normal, depth) = Shape::rec_to_cir(&self, shape, &mut gizmos);
+                let (normal, depth) = Shape::rec_to_cir(self, shape);
                 (-normal, depth)
             }
             (Poly(_, _), Circle(_)) => {
-                let (normal, depth) = Shape::rec_to_cir(&self, shape, &mut gizmos);
+                let (normal, depth) = Shape::rec_to_cir(self, shape);
                 (-normal, depth)
             }
-            (Circle(_), Rec(_, _)) => Shape::rec_to_cir(shape, &self, &mut gizmos),
-            (Circle(_), Poly(_, _)) => Shape::rec_to_cir(shape, &self, &mut gizmos),
+            (Circle(_), Rec(_, _)) => Shape::rec_to_cir(shape, self),
+            (Circle(_), Poly(_, _)) => Shape::rec_to_cir(shape, self),
             _ => self.rec_to_rec(shape),
         }
     }
 
-    fn proyect_vecs(vertices: &Vec<Vec2>, axis: Vec2, pos: Vec2) -> (f32, f32) {
+    fn proyect_vecs(vertices: &Vec<Vec2>, axis: Vec2) -> (f32, f32) {
         let mut max = std::f32::MIN;
         let mut min = std::f32::MAX;
 
         for i in vertices {
-            let proj = Vec2::dot(*i + pos, axis.normalize());
+            let proj = Vec2::dot(*i, axis.normalize());
             if proj > max {
                 max = proj
             }
@@ -43,20 +98,6 @@ impl Shape {
         }
         (max, min)
     }
-    pub fn rotate(&mut self, angle: f32) {
-        match self.kind {
-            Circle(_) => {}
-            _ => {
-                for i in &mut self.vertices {
-                    let cos = angle.cos();
-                    let sin = angle.sin();
-                    let rx = cos * i.x - sin * i.y;
-                    let ry = sin * i.x + cos * i.y;
-                    *i = Vec2::new(rx, ry);
-                }
-            }
-        }
-    }
     fn cir_to_cir(&self, ra: f32, rb: f32, pb: Vec2) -> (Vec2, f32) {
         let distance = self.pos.distance(pb);
         let rad = ra + rb;
@@ -72,15 +113,15 @@ impl Shape {
         let mut depth = f32::MAX;
         let mut axis;
         let mut normal = Vec2::ZERO;
-        for (i, _) in self.vertices.iter().enumerate() {
-            let va = &self.vertices[i];
-            let vb = &self.vertices[(i + 1) % self.vertices.len()];
+        for (i, _) in self.world_vertices.iter().enumerate() {
+            let va = &self.world_vertices[i];
+            let vb = &self.world_vertices[(i + 1) % self.world_vertices.len()];
 
             axis = *vb - *va;
             axis = Vec2::new(-axis.y, axis.x);
 
-            let (maxa, mina) = Shape::proyect_vecs(&self.vertices, axis, self.pos);
-            let (maxb, minb) = Shape::proyect_vecs(&shape.vertices, axis, shape.pos);
+            let (maxa, mina) = Shape::proyect_vecs(&self.world_vertices, axis);
+            let (maxb, minb) = Shape::proyect_vecs(&shape.world_vertices, axis);
             if mina >= maxb || minb >= maxa {
                 return (Vec2::ZERO, 0.);
             }
@@ -99,15 +140,15 @@ impl Shape {
             normal = -normal;
         }
 
-        for (i, _) in shape.vertices.iter().enumerate() {
-            let va = &shape.vertices[i];
-            let vb = &shape.vertices[(i + 1) % shape.vertices.len()];
+        for (i, _) in shape.world_vertices.iter().enumerate() {
+            let va = &shape.world_vertices[i];
+            let vb = &shape.world_vertices[(i + 1) % shape.world_vertices.len()];
 
             axis = *vb - *va;
             axis = Vec2::new(-axis.y, axis.x);
 
-            let (maxa, mina) = Shape::proyect_vecs(&shape.vertices, axis, shape.pos);
-            let (maxb, minb) = Shape::proyect_vecs(&self.vertices, axis, self.pos);
+            let (maxa, mina) = Shape::proyect_vecs(&shape.world_vertices, axis);
+            let (maxb, minb) = Shape::proyect_vecs(&self.world_vertices, axis);
             if mina >= maxb || minb >= maxa {
                 return (Vec2::ZERO, 0.);
             }
@@ -129,7 +170,7 @@ impl Shape {
         (normal.normalize(), depth)
     }
 
-    fn rec_to_cir(slf: &Shape, shape: &Shape, mut _gizmos: &mut Gizmos) -> (Vec2, f32) {
+    fn rec_to_cir(slf: &Shape, shape: &Shape) -> (Vec2, f32) {
         let radius = match shape.kind {
             Circle(r) => r,
             _ => 0.,
@@ -137,14 +178,14 @@ impl Shape {
         let mut depth = f32::MAX;
         let mut normal = Vec2::ZERO;
         let mut axis;
-        for (i, _) in slf.vertices.iter().enumerate() {
-            let va = &slf.vertices[i];
-            let vb = &slf.vertices[(i + 1) % slf.vertices.len()];
+        for (i, _) in slf.world_vertices.iter().enumerate() {
+            let va = &slf.world_vertices[i];
+            let vb = &slf.world_vertices[(i + 1) % slf.world_vertices.len()];
 
             axis = *vb - *va;
             axis = Vec2::new(-axis.y, axis.x);
 
-            let (maxa, mina) = Shape::proyect_vecs(&slf.vertices, axis, slf.pos);
+            let (maxa, mina) = Shape::proyect_vecs(&slf.world_vertices, axis);
             let (maxb, minb) = Shape::proyect_cir(shape.pos, radius, axis);
             if mina >= maxb || minb >= maxa {
                 return (Vec2::ZERO, 0.);
@@ -156,9 +197,9 @@ impl Shape {
             }
         }
 
-        let cpindex = Shape::close_point(shape.pos, &slf.vertices, slf.pos);
-        let axi = shape.pos - (slf.vertices[cpindex] + slf.pos);
-        let (maxa, mina) = Shape::proyect_vecs(&slf.vertices, axi, slf.pos);
+        let cpindex = Shape::close_point(shape.pos, &slf.world_vertices);
+        let axi = shape.pos - slf.world_vertices[cpindex];
+        let (maxa, mina) = Shape::proyect_vecs(&slf.world_vertices, axi);
         let (maxb, minb) = Shape::proyect_cir(shape.pos, radius, axi);
         if mina >= maxb || minb >= maxa {
             return (Vec2::ZERO, 0.);
@@ -181,12 +222,12 @@ impl Shape {
         (normal.normalize(), depth)
     }
 
-    fn close_point(center: Vec2, vertices: &Vec<Vec2>, pos: Vec2) -> usize {
+    fn close_point(center: Vec2, vertices: &Vec<Vec2>) -> usize {
         let mut result = 0;
         let mut min_distance = f32::MAX;
 
         for (i, v) in vertices.iter().enumerate() {
-            let distance = Vec2::distance(*v + pos, center);
+            let distance = Vec2::distance(*v, center);
             if distance < min_distance {
                 min_distance = distance;
                 result = i;
@@ -217,76 +258,110 @@ impl Shape {
         maxa.x <= minb.x || maxb.x <= mina.x || maxa.y <= minb.y || maxb.y <= mina.y
     }
 
-    pub fn contactpoint(&self, shape: &Shape) -> (Vec2, Vec2, i32) {
+    pub fn contactpoint(
+        &self,
+        shape: &Shape,
+        normal: Vec2,
+        depth: f32,
+    ) -> ([Vec2; 2], [f32; 2], usize) {
+        let single = |point| ([point, Vec2::ZERO], [depth, 0.], 1);
         match (&self.kind, &shape.kind) {
             (Circle(ra), Circle(_)) => {
                 let ab = self.pos - shape.pos;
                 let dir = ab.normalize_or_zero();
-                (self.pos - dir * *ra, Vec2::ZERO, 1)
+                single(self.pos - dir * *ra)
             }
-            (Rec(_, _), Circle(_)) => (Shape::point_rec_to_cir(self, shape), Vec2::ZERO, 1),
-            (Poly(_, _), Circle(_)) => (Shape::point_rec_to_cir(self, shape), Vec2::ZERO, 1),
-            (Circle(_), Poly(_, _)) => (Shape::point_rec_to_cir(shape, self), Vec2::ZERO, 1),
-            (Circle(_), Rec(_, _)) => (Shape::point_rec_to_cir(shape, self), Vec2::ZERO, 1),
-            _ => self.point_rec_to_rec(shape),
+            (Rec(_, _), Circle(_)) => single(Shape::point_rec_to_cir(self, shape)),
+            (Poly(_, _), Circle(_)) => single(Shape::point_rec_to_cir(self, shape)),
+            (Circle(_), Poly(_, _)) => single(Shape::point_rec_to_cir(shape, self)),
+            (Circle(_), Rec(_, _)) => single(Shape::point_rec_to_cir(shape, self)),
+            _ => self.point_rec_to_rec(shape, normal),
         }
     }
 
-    fn point_rec_to_rec(&self, shape: &Shape) -> (Vec2, Vec2, i32) {
-        let mut contact1 = Vec2::ZERO;
-        let mut contact2 = Vec2::ZERO;
-        let mut cc = 0;
-        let mut min = f32::MAX;
+    // Clips the incident edge against the side planes of the reference edge, the
+    // one of the two facing edges most perpendicular to `normal`, and keeps the
+    // clipped points that are behind the reference face.
+    fn point_rec_to_rec(&self, shape: &Shape, normal: Vec2) -> ([Vec2; 2], [f32; 2], usize) {
+        let edge_a = Shape::best_edge(&self.world_vertices, normal);
+        let edge_b = Shape::best_edge(&shape.world_vertices, -normal);
+        let dir_a = (edge_a.1 - edge_a.0).normalize_or_zero();
+        let dir_b = (edge_b.1 - edge_b.0).normalize_or_zero();
+        let (reference, incident, dir, out) = if dir_a.dot(normal).abs() <= dir_b.dot(normal).abs()
+        {
+            (edge_a, edge_b, dir_a, normal)
+        } else {
+            (edge_b, edge_a, dir_b, -normal)
+        };
 
-        for p in &self.vertices {
-            let p = *p + self.pos;
-            for (i, a) in shape.vertices.iter().enumerate() {
-                let va = *a + shape.pos;
-                let vb = shape.vertices[(i + 1) % shape.vertices.len()] + shape.pos;
-
-                let (distsq, cp) = Shape::point_segment(p, va, vb);
-
-                if prox_equals_f32(distsq, min) {
-                    if !prox_equals_vec2(cp, contact1) {
-                        cc = 2;
-                        contact2 = cp;
-                    }
-                } else if distsq < min {
-                    min = distsq;
-                    cc = 1;
-                    contact1 = cp;
-                }
+        let points = Shape::clip(incident.0, incident.1, dir, dir.dot(reference.0));
+        let points = Shape::clip(points[0], points[1], -dir, -dir.dot(reference.1));
+        let mut face_normal = Vec2::new(-dir.y, dir.x);
+        if face_normal.dot(out) < 0. {
+            face_normal = -face_normal;
+        }
+        let face = face_normal.dot(reference.0);
+
+        let mut contacts = [Vec2::ZERO; 2];
+        let mut depths = [0.; 2];
+        let mut count = 0;
+        for p in points {
+            let depth = face - face_normal.dot(p);
+            if depth >= 0. {
+                contacts[count] = p;
+                depths[count] = depth;
+                count += 1;
             }
         }
-        for p in &shape.vertices {
-            let p = *p + shape.pos;
-            for (i, a) in self.vertices.iter().enumerate() {
-                let va = *a + self.pos;
-                let vb = self.vertices[(i + 1) % self.vertices.len()] + self.pos;
-
-                let (distsq, cp) = Shape::point_segment(p, va, vb);
-
-                if prox_equals_f32(distsq, min) {
-                    if !prox_equals_vec2(cp, contact1) {
-                        cc = 2;
-                        contact2 = cp;
-                    }
-                } else if distsq < min {
-                    min = distsq;
-                    cc = 1;
-                    contact1 = cp;
-                }
+        (contacts, depths, count)
+    }
+
+    // Edge around the vertex furthest along `normal` that is most perpendicular to it.
+    fn best_edge(vertices: &[Vec2], normal: Vec2) -> (Vec2, Vec2) {
+        let count = vertices.len();
+        let mut index = 0;
+        for (i, v) in vertices.iter().enumerate() {
+            if v.dot(normal) > vertices[index].dot(normal) {
+                index = i;
             }
         }
-        (contact1, contact2, cc)
+        let v = vertices[index];
+        let prev = vertices[(index + count - 1) % count];
+        let next = vertices[(index + 1) % count];
+        let left = (v - prev).normalize_or_zero();
+        let right = (next - v).normalize_or_zero();
+        if right.dot(normal).abs() <= left.dot(normal).abs() {
+            (v, next)
+        } else {
+            (prev, v)
+        }
+    }
+
+    // Part of the segment `a`-`b` whose projection on `dir` is at least `offset`.
+    fn clip(a: Vec2, b: Vec2, dir: Vec2, offset: f32) -> [Vec2; 2] {
+        let da = dir.dot(a) - offset;
+        let db = dir.dot(b) - offset;
+        if da >= 0. && db >= 0. {
+            return [a, b];
+        }
+        if da < 0. && db < 0. {
+            // Both sides are outside, the edges barely overlap.
+            return if da > db { [a, a] } else { [b, b] };
+        }
+        let cut = a + (b - a) * (da / (da - db));
+        if da < 0. {
+            [cut, b]
+        } else {
+            [a, cut]
+        }
     }
 
     fn point_rec_to_cir(slf: &Shape, shape: &Shape) -> Vec2 {
         let mut min = f32::MAX;
         let mut cp = Vec2::ZERO;
-        for (i, v) in slf.vertices.iter().enumerate() {
-            let va = *v + slf.pos;
-            let vb = slf.vertices[(i + 1) % slf.vertices.len()] + slf.pos;
+        for (i, v) in slf.world_vertices.iter().enumerate() {
+            let va = *v;
+            let vb = slf.world_vertices[(i + 1) % slf.world_vertices.len()];
             let (distancesqr, contact) = Shape::point_segment(shape.pos, va, vb);
             if distancesqr < min {
                 min = distancesqr;
@@ -0,0 +1,73 @@
+use bevy::prelude::*;
+
+use super::body::Shape;
+
+// When enabled, bodies and pairs are processed sorted by entity instead of in
+// query order, so two runs fed the same inputs produce bit identical results on
+// the same platform. `seed` feeds the random numbers used by the demo.
+#[derive(Resource, Default)]
+pub struct Determinism {
+    pub enabled: bool,
+    pub seed: u64,
+}
+
+impl Determinism {
+    // Sorts `items` by `key`, usually their entities, when the mode is enabled.
+    pub fn order<T, K: Ord>(&self, items: &mut [T], key: impl FnMut(&T) -> K) {
+        if self.enabled {
+            items.sort_by_key(key);
+        }
+    }
+}
+
+// Hash of the state of every body after the last simulated step. Peers running
+// in lockstep compare `value` for the same `step` to detect divergence.
+#[derive(Resource, Default)]
+pub struct WorldChecksum {
+    pub step: u64,
+    pub value: u64,
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn hash_bits(hash: &mut u64, bits: u32) {
+    for byte in bits.to_le_bytes() {
+        *hash ^= byte as u64;
+        *hash = hash.wrapping_mul(FNV_PRIME);
+    }
+}
+
+// FNV-1a over the raw bits of the pose and velocities of every body, in entity order.
+pub fn checksum<'a>(bodies: impl IntoIterator<Item = (Entity, &'a Shape)>) -> u64 {
+    let mut bodies: Vec<_> = bodies.into_iter().collect();
+    bodies.sort_by_key(|(entity, _)| *entity);
+
+    let mut hash = FNV_OFFSET;
+    for (entity, shape) in bodies {
+        hash_bits(&mut hash, entity.index());
+        hash_bits(&mut hash, entity.generation());
+        for value in [
+            shape.pos.x,
+            shape.pos.y,
+            shape.rot,
+            shape.vel.x,
+            shape.vel.y,
+            shape.rot_vel,
+        ] {
+            hash_bits(&mut hash, value.to_bits());
+        }
+    }
+    hash
+}
+
+pub fn update_checksum(
+    determinism: Res<Determinism>,
+    mut world_checksum: ResMut<WorldChecksum>,
+    query: Query<(Entity, &Shape)>,
+) {
+    world_checksum.step += 1;
+    if determinism.enabled {
+        world_checksum.value = checksum(query.iter());
+    }
+}
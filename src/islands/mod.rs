@@ -0,0 +1,187 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use super::body::Shape;
+use super::broadphase::PhysicsBroadphase;
+use super::determinism::Determinism;
+use super::integrator::{Gravity, SubstepTime};
+use super::joints::Joint;
+
+// Marker added to every body of an island that fell asleep. Sleeping bodies are
+// skipped by the integrator and the solver until something wakes them up.
+#[derive(Component)]
+pub struct Sleeping;
+
+#[derive(Resource)]
+pub struct SleepSettings {
+    pub enabled: bool,
+    pub linear_threshold: f32,
+    pub angular_threshold: f32,
+    pub time_to_sleep: f32,
+}
+
+impl std::default::Default for SleepSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            linear_threshold: 5.,
+            angular_threshold: f32::to_radians(3.),
+            time_to_sleep: 0.5,
+        }
+    }
+}
+
+#[derive(Resource, Default, Clone)]
+pub struct Islands {
+    // Dynamic bodies of every island solved during the last step.
+    pub awake: Vec<Vec<Entity>>,
+    sleeping: HashMap<usize, Vec<Entity>>,
+    sleeping_bodies: HashMap<Entity, (usize, Vec2, f32)>,
+    next_id: usize,
+}
+
+impl Islands {
+    pub fn is_sleeping(&self, entity: Entity) -> bool {
+        self.sleeping_bodies.contains_key(&entity)
+    }
+
+    pub fn sleep(&mut self, bodies: Vec<(Entity, Vec2, f32)>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        for (entity, pos, rot) in &bodies {
+            self.sleeping_bodies.insert(*entity, (id, *pos, *rot));
+        }
+        self.sleeping
+            .insert(id, bodies.into_iter().map(|(e, _, _)| e).collect());
+    }
+
+    // Wakes the whole island `entity` sleeps in and returns its bodies.
+    pub fn wake(&mut self, entity: Entity) -> Vec<Entity> {
+        let Some((id, _, _)) = self.sleeping_bodies.get(&entity) else {
+            return vec![];
+        };
+        let bodies = self.sleeping.remove(id).unwrap_or_default();
+        for e in &bodies {
+            self.sleeping_bodies.remove(e);
+        }
+        bodies
+    }
+}
+
+pub fn wake_island(commands: &mut Commands, islands: &mut Islands, entity: Entity) {
+    for e in islands.wake(entity) {
+        if let Some(mut body) = commands.get_entity(e) {
+            body.remove::<Sleeping>();
+        }
+    }
+}
+
+// Wakes sleeping bodies whose state was touched by game code since they fell
+// asleep, and the ones that lost a body they rested on or were jointed to.
+pub fn wake_bodies(
+    mut commands: Commands,
+    mut islands: ResMut<Islands>,
+    query: Query<(Entity, &Shape), With<Sleeping>>,
+    mut removed: RemovedComponents<Shape>,
+    gravity: Res<Gravity>,
+    broadphase: Res<PhysicsBroadphase>,
+    joints: Query<&Joint>,
+) {
+    for entity in removed.read() {
+        // The proxy is only removed by `update_broadphase`, later in the step.
+        let mut touched = broadphase
+            .0
+            .aabb(entity)
+            .map_or(vec![], |aabb| broadphase.0.query_region(&aabb));
+        for joint in joints.iter() {
+            if joint.a == entity {
+                touched.push(joint.body_b());
+            } else if joint.b == Some(entity) {
+                touched.push(joint.a);
+            }
+        }
+        touched.push(entity);
+        for e in touched {
+            wake_island(&mut commands, &mut islands, e);
+        }
+    }
+    for (entity, shape) in query.iter() {
+        let Some((_, pos, rot)) = islands.sleeping_bodies.get(&entity) else {
+            continue;
+        };
+        let edited = gravity.is_changed()
+            || !shape.body.is_dynamic()
+            || shape.pos != *pos
+            || shape.vel != Vec2::ZERO
+            || shape.rot_vel != 0.
+            || shape.rot != *rot
+            || shape.force != Vec2::ZERO
+            || shape.torque != 0.;
+        if edited {
+            wake_island(&mut commands, &mut islands, entity);
+        }
+    }
+}
+
+pub fn update_sleep(
+    mut commands: Commands,
+    mut islands: ResMut<Islands>,
+    settings: Res<SleepSettings>,
+    mut query: Query<(Entity, &mut Shape), Without<Sleeping>>,
+    step: Res<SubstepTime>,
+    determinism: Res<Determinism>,
+) {
+    let time = step.0;
+
+    for (_, mut shape) in query.iter_mut() {
+        if !shape.body.is_dynamic() {
+            continue;
+        }
+        if !settings.enabled
+            || shape.vel.length() > settings.linear_threshold
+            || shape.rot_vel.abs() > settings.angular_threshold
+        {
+            shape.sleep_time = 0.;
+        } else {
+            shape.sleep_time += time;
+        }
+    }
+    if !settings.enabled {
+        return;
+    }
+
+    let mut candidates = std::mem::take(&mut islands.awake);
+    let in_island: HashSet<Entity> = candidates.iter().flatten().copied().collect();
+    let mut alone: Vec<Vec<Entity>> = query
+        .iter()
+        .filter(|(entity, shape)| shape.body.is_dynamic() && !in_island.contains(entity))
+        .map(|(entity, _)| vec![entity])
+        .collect();
+    determinism.order(&mut alone, |island| island[0]);
+    candidates.extend(alone);
+
+    for island in candidates {
+        let tired = island.iter().all(|e| {
+            query
+                .get(*e)
+                .is_ok_and(|(_, shape)| shape.sleep_time >= settings.time_to_sleep)
+        });
+        if !tired {
+            continue;
+        }
+        let mut bodies = vec![];
+        for e in island {
+            let Ok((_, mut shape)) = query.get_mut(e) else {
+                continue;
+            };
+            shape.vel = Vec2::ZERO;
+            shape.rot_vel = 0.;
+            shape.sleep_time = 0.;
+            bodies.push((e, shape.pos, shape.rot));
+            commands.entity(e).insert(Sleeping);
+        }
+        islands.sleep(bodies);
+    }
+}
@@ -0,0 +1,1022 @@
+use bevy::prelude::*;
+
+use super::body::Shape;
+use super::integrator::SubstepTime;
+use super::islands::Sleeping;
+use super::solver::{pair_mut, SolverBody, SolverSettings};
+use super::xpbd::{
+    apply_angular_position_impulse, apply_angular_velocity_impulse, apply_position_impulse,
+    apply_velocity_impulse, delta_lambda, generalized_inv_mass, point_velocity, rotated,
+};
+
+// Keeps the distance between the anchors in `min_length..=max_length`. A rod
+// has both equal to `length`, a rope only limits the maximum.
+#[derive(Clone, Copy, Debug)]
+pub struct DistanceJoint {
+    pub length: f32,
+    pub min_length: f32,
+    pub max_length: f32,
+    // Pull towards `length`, which replaces the range when `min_length` equals
+    // `max_length`. `None` leaves the distance free inside the range and holds
+    // an empty one rigidly. Compliance is the inverse of the stiffness,
+    // `Some(0.)` holds the distance rigidly at `length` in both solver backends.
+    pub compliance: Option<f32>,
+}
+
+impl DistanceJoint {
+    pub fn new(length: f32) -> DistanceJoint {
+        DistanceJoint {
+            length,
+            min_length: length,
+            max_length: length,
+            compliance: None,
+        }
+    }
+}
+
+// Keeps the anchors together and lets the bodies turn around them. The angle of
+// `a` relative to `b` or the world, minus `reference_angle`, stays in
+// `min_angle..=max_angle`.
+#[derive(Clone, Copy, Debug)]
+pub struct RevoluteJoint {
+    pub reference_angle: f32,
+    pub min_angle: f32,
+    pub max_angle: f32,
+    // Drives the angular velocity of `a` relative to `b` towards `motor_speed`
+    // with at most `max_motor_torque`, a torque of 0 disables the motor.
+    pub motor_speed: f32,
+    pub max_motor_torque: f32,
+}
+
+impl RevoluteJoint {
+    pub fn new() -> RevoluteJoint {
+        RevoluteJoint {
+            reference_angle: 0.,
+            min_angle: f32::NEG_INFINITY,
+            max_angle: f32::INFINITY,
+            motor_speed: 0.,
+            max_motor_torque: 0.,
+        }
+    }
+}
+
+impl std::default::Default for RevoluteJoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Lets the anchor of `a` slide along `axis`, fixed in the frame of `b` or of the
+// world, and keeps the angle between the bodies at `reference_angle`. The
+// translation of the anchor of `a` from the one of `b` along the axis stays in
+// `min_translation..=max_translation`.
+#[derive(Clone, Copy, Debug)]
+pub struct PrismaticJoint {
+    pub axis: Vec2,
+    pub reference_angle: f32,
+    pub min_translation: f32,
+    pub max_translation: f32,
+    // Drives the speed along the axis towards `motor_speed` with at most
+    // `max_motor_force`, a force of 0 disables the motor.
+    pub motor_speed: f32,
+    pub max_motor_force: f32,
+}
+
+impl PrismaticJoint {
+    pub fn new(axis: Vec2) -> PrismaticJoint {
+        PrismaticJoint {
+            axis: axis.normalize_or_zero(),
+            reference_angle: 0.,
+            min_translation: f32::NEG_INFINITY,
+            max_translation: f32::INFINITY,
+            motor_speed: 0.,
+            max_motor_force: 0.,
+        }
+    }
+
+    fn world_axis(&self, b: &SolverBody) -> Vec2 {
+        Vec2::from_angle(b.rot).rotate(self.axis)
+    }
+}
+
+// Locks the anchors together and the angle between the bodies at
+// `reference_angle`. Compliances above 0 let it flex like a spring.
+#[derive(Clone, Copy, Debug)]
+pub struct WeldJoint {
+    pub reference_angle: f32,
+    pub linear_compliance: f32,
+    pub angular_compliance: f32,
+    // The joint entity is despawned once the force or the torque it applies
+    // goes past these, sending a `JointBroken` event.
+    pub break_force: f32,
+    pub break_torque: f32,
+}
+
+impl WeldJoint {
+    pub fn new() -> WeldJoint {
+        WeldJoint {
+            reference_angle: 0.,
+            linear_compliance: 0.,
+            angular_compliance: 0.,
+            break_force: f32::INFINITY,
+            break_torque: f32::INFINITY,
+        }
+    }
+}
+
+impl std::default::Default for WeldJoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SpringMode {
+    // Explicit forces applied to the bodies every substep, stiff springs need
+    // small steps to stay stable.
+    Force,
+    // Soft constraint solved with the contacts, stable at any stiffness.
+    #[default]
+    Soft,
+}
+
+// Pulls the anchors towards `rest_length` with a force of `stiffness` per unit
+// of stretch, `damping` resists the relative speed along the spring.
+#[derive(Clone, Copy, Debug)]
+pub struct SpringJoint {
+    pub rest_length: f32,
+    pub stiffness: f32,
+    pub damping: f32,
+    pub mode: SpringMode,
+}
+
+impl SpringJoint {
+    pub fn new(rest_length: f32, stiffness: f32, damping: f32) -> SpringJoint {
+        SpringJoint {
+            rest_length,
+            stiffness,
+            damping,
+            mode: SpringMode::default(),
+        }
+    }
+
+    // Force pushing the anchors apart given their distance and relative speed.
+    fn force(&self, len: f32, speed: f32) -> f32 {
+        self.stiffness * (self.rest_length - len) - self.damping * speed
+    }
+
+    fn solved(&self) -> bool {
+        self.mode == SpringMode::Soft && (self.stiffness > 0. || self.damping > 0.)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum JointKind {
+    Distance(DistanceJoint),
+    Revolute(RevoluteJoint),
+    Prismatic(PrismaticJoint),
+    Weld(WeldJoint),
+    Spring(SpringJoint),
+}
+
+// Impulses kept by a joint between steps, their meaning depends on the kind.
+pub type JointImpulses = [f32; 5];
+
+// Constraint between two bodies, spawned on its own entity. Jointed bodies are
+// always solved in the same island, a joint with `b` equal to `a` is ignored.
+#[derive(Component, Clone, Debug)]
+pub struct Joint {
+    pub kind: JointKind,
+    pub a: Entity,
+    // `None` pins the joint to the world, `anchor_b` is then a world point.
+    pub b: Option<Entity>,
+    // Anchors in the body frames, relative to `Shape::pos`.
+    pub anchor_a: Vec2,
+    pub anchor_b: Vec2,
+    pub collide_connected: bool,
+    // Impulses applied during the last step, used to warm start the next one.
+    pub impulses: JointImpulses,
+}
+
+impl Joint {
+    pub fn new(
+        kind: JointKind,
+        a: Entity,
+        b: Option<Entity>,
+        anchor_a: Vec2,
+        anchor_b: Vec2,
+    ) -> Joint {
+        Joint {
+            kind,
+            a,
+            b,
+            anchor_a,
+            anchor_b,
+            collide_connected: false,
+            impulses: JointImpulses::default(),
+        }
+    }
+
+    // Entity used for the body `b`, the world is a static body at the origin.
+    pub fn body_b(&self) -> Entity {
+        self.b.unwrap_or(Entity::PLACEHOLDER)
+    }
+}
+
+// Sent when a joint is despawned for going past its break force or torque.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct JointBroken {
+    pub joint: Entity,
+    pub a: Entity,
+    pub b: Option<Entity>,
+}
+
+// A joint prepared for one step of the solver.
+pub struct JointConstraint {
+    pub entity: Entity,
+    pub body_a: Entity,
+    pub body_b: Entity,
+    // Indices of the bodies in their island, set by `build_islands`.
+    pub a: usize,
+    pub b: usize,
+    // Offsets of the anchors from the centers of mass at the start of the step.
+    pub ra: Vec2,
+    pub rb: Vec2,
+    pub kind: JointKind,
+    pub impulses: JointImpulses,
+    // Lagrange multipliers accumulated by the XPBD backend during the step.
+    pub lambdas: JointImpulses,
+}
+
+impl JointConstraint {
+    pub fn new(entity: Entity, joint: &Joint, ra: Vec2, rb: Vec2) -> JointConstraint {
+        JointConstraint {
+            entity,
+            body_a: joint.a,
+            body_b: joint.body_b(),
+            a: 0,
+            b: 0,
+            ra,
+            rb,
+            kind: joint.kind,
+            impulses: joint.impulses,
+            lambdas: JointImpulses::default(),
+        }
+    }
+
+    pub fn warm_start(&self, bodies: &mut [SolverBody]) {
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        match self.kind {
+            JointKind::Distance(_) => {
+                let n = (b.pos + self.rb - a.pos - self.ra).normalize_or_zero();
+                let impulse = self.impulses[0] + self.impulses[1] - self.impulses[2];
+                apply_velocity_impulse(a, b, self.ra, self.rb, n * impulse);
+            }
+            JointKind::Revolute(_) => {
+                let impulse = Vec2::new(self.impulses[0], self.impulses[1]);
+                apply_velocity_impulse(a, b, self.ra, self.rb, impulse);
+                let angular = self.impulses[2] + self.impulses[3] - self.impulses[4];
+                apply_angular_velocity_impulse(b, a, angular);
+            }
+            JointKind::Prismatic(joint) => {
+                let axis = joint.world_axis(b);
+                let arm = a.pos + self.ra - b.pos;
+                let along = self.impulses[2] + self.impulses[3] - self.impulses[4];
+                let impulse = axis.perp() * self.impulses[0] + axis * along;
+                apply_velocity_impulse(a, b, self.ra, arm, -impulse);
+                apply_angular_velocity_impulse(a, b, self.impulses[1]);
+            }
+            JointKind::Weld(_) => {
+                let impulse = Vec2::new(self.impulses[0], self.impulses[1]);
+                apply_velocity_impulse(a, b, self.ra, self.rb, impulse);
+                apply_angular_velocity_impulse(a, b, self.impulses[2]);
+            }
+            JointKind::Spring(_) => {
+                let n = (b.pos + self.rb - a.pos - self.ra).normalize_or_zero();
+                apply_velocity_impulse(a, b, self.ra, self.rb, n * self.impulses[0]);
+            }
+        }
+    }
+
+    pub fn solve_velocity(
+        &mut self,
+        bodies: &mut [SolverBody],
+        settings: &SolverSettings,
+        time: f32,
+    ) {
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let (ra, rb) = (self.ra, self.rb);
+        match self.kind {
+            JointKind::Distance(joint) => {
+                let d = (b.pos + rb) - (a.pos + ra);
+                let len = d.length();
+                if len <= f32::EPSILON {
+                    return;
+                }
+                let n = d / len;
+                let w = generalized_inv_mass(a, ra, n) + generalized_inv_mass(b, rb, n);
+                if w <= 0. {
+                    return;
+                }
+                let cdot = |a: &SolverBody, b: &SolverBody| {
+                    (point_velocity(b, rb) - point_velocity(a, ra)).dot(n)
+                };
+
+                if let Some(compliance) = joint.compliance {
+                    let (gamma, rate) = if compliance > 0. {
+                        soft(1. / compliance, 0., time)
+                    } else {
+                        (0., settings.correction_factor / time)
+                    };
+                    let c = len - joint.length;
+                    let impulse = -(cdot(a, b) + rate * c + gamma * self.impulses[0]) / (w + gamma);
+                    self.impulses[0] += impulse;
+                    apply_velocity_impulse(a, b, ra, rb, n * impulse);
+                } else if joint.min_length >= joint.max_length {
+                    let bias = settings.correction_factor * (len - joint.min_length) / time;
+                    let impulse = -(cdot(a, b) + bias) / w;
+                    self.impulses[0] += impulse;
+                    apply_velocity_impulse(a, b, ra, rb, n * impulse);
+                }
+                if joint.min_length >= joint.max_length {
+                    return;
+                }
+
+                let c = len - joint.min_length;
+                let impulse =
+                    limit_impulse(&mut self.impulses[1], c, cdot(a, b), w, settings, time);
+                apply_velocity_impulse(a, b, ra, rb, n * impulse);
+
+                if joint.max_length.is_finite() {
+                    let c = joint.max_length - len;
+                    let impulse =
+                        limit_impulse(&mut self.impulses[2], c, -cdot(a, b), w, settings, time);
+                    apply_velocity_impulse(a, b, ra, rb, -n * impulse);
+                }
+            }
+            JointKind::Revolute(joint) => {
+                let w = a.inv_inertia + b.inv_inertia;
+                if w > 0. {
+                    // Angular impulses turn `a` forward and `b` back.
+                    if joint.max_motor_torque > 0. {
+                        let cdot = a.rot_vel - b.rot_vel - joint.motor_speed;
+                        let max = joint.max_motor_torque * time;
+                        let old = self.impulses[2];
+                        self.impulses[2] = (old - cdot / w).clamp(-max, max);
+                        apply_angular_velocity_impulse(b, a, self.impulses[2] - old);
+                    }
+
+                    let angle = a.rot - b.rot - joint.reference_angle;
+                    if joint.min_angle.is_finite() {
+                        let (c, cdot) = (angle - joint.min_angle, a.rot_vel - b.rot_vel);
+                        let impulse =
+                            limit_impulse(&mut self.impulses[3], c, cdot, w, settings, time);
+                        apply_angular_velocity_impulse(b, a, impulse);
+                    }
+                    if joint.max_angle.is_finite() {
+                        let (c, cdot) = (joint.max_angle - angle, b.rot_vel - a.rot_vel);
+                        let impulse =
+                            limit_impulse(&mut self.impulses[4], c, cdot, w, settings, time);
+                        apply_angular_velocity_impulse(b, a, -impulse);
+                    }
+                }
+
+                let rate = settings.correction_factor / time;
+                solve_point(a, b, ra, rb, (0., rate), &mut self.impulses[..2]);
+            }
+            JointKind::Prismatic(joint) => {
+                // Impulses act on both bodies at the anchor of `a`, which also
+                // accounts for the axis turning with `b`. They push `a` along
+                // their direction and `b` the other way.
+                let axis = joint.world_axis(b);
+                let arm = a.pos + ra - b.pos;
+                let d = arm - rb;
+                let translation = axis.dot(d);
+                let mass = |a: &SolverBody, b: &SolverBody, n: Vec2| {
+                    generalized_inv_mass(a, ra, n) + generalized_inv_mass(b, arm, n)
+                };
+                let cdot = |a: &SolverBody, b: &SolverBody, n: Vec2| {
+                    (point_velocity(a, ra) - point_velocity(b, arm)).dot(n)
+                };
+
+                let w = mass(a, b, axis);
+                if w > 0. {
+                    if joint.max_motor_force > 0. {
+                        let max = joint.max_motor_force * time;
+                        let old = self.impulses[2];
+                        let impulse = old - (cdot(a, b, axis) - joint.motor_speed) / w;
+                        self.impulses[2] = impulse.clamp(-max, max);
+                        apply_velocity_impulse(a, b, ra, arm, -axis * (self.impulses[2] - old));
+                    }
+                    if joint.min_translation.is_finite() {
+                        let c = translation - joint.min_translation;
+                        let cdot = cdot(a, b, axis);
+                        let impulse =
+                            limit_impulse(&mut self.impulses[3], c, cdot, w, settings, time);
+                        apply_velocity_impulse(a, b, ra, arm, -axis * impulse);
+                    }
+                    if joint.max_translation.is_finite() {
+                        let c = joint.max_translation - translation;
+                        let cdot = -cdot(a, b, axis);
+                        let impulse =
+                            limit_impulse(&mut self.impulses[4], c, cdot, w, settings, time);
+                        apply_velocity_impulse(a, b, ra, arm, axis * impulse);
+                    }
+                }
+
+                let w = a.inv_inertia + b.inv_inertia;
+                if w > 0. {
+                    let c = b.rot - a.rot - joint.reference_angle;
+                    let cdot = b.rot_vel - a.rot_vel;
+                    let impulse = -(cdot + settings.correction_factor * c / time) / w;
+                    self.impulses[1] += impulse;
+                    apply_angular_velocity_impulse(a, b, impulse);
+                }
+
+                let perp = axis.perp();
+                let w = mass(a, b, perp);
+                if w > 0. {
+                    let c = perp.dot(d);
+                    let impulse = -(cdot(a, b, perp) + settings.correction_factor * c / time) / w;
+                    self.impulses[0] += impulse;
+                    apply_velocity_impulse(a, b, ra, arm, -perp * impulse);
+                }
+            }
+            JointKind::Weld(joint) => {
+                let softness = |compliance: f32| {
+                    if compliance > 0. {
+                        soft(1. / compliance, 0., time)
+                    } else {
+                        (0., settings.correction_factor / time)
+                    }
+                };
+
+                let w = a.inv_inertia + b.inv_inertia;
+                let (gamma, rate) = softness(joint.angular_compliance);
+                if w + gamma > 0. {
+                    let c = b.rot - a.rot - joint.reference_angle;
+                    let cdot = b.rot_vel - a.rot_vel;
+                    let impulse = -(cdot + rate * c + gamma * self.impulses[2]) / (w + gamma);
+                    self.impulses[2] += impulse;
+                    apply_angular_velocity_impulse(a, b, impulse);
+                }
+
+                let softness = softness(joint.linear_compliance);
+                solve_point(a, b, ra, rb, softness, &mut self.impulses[..2]);
+            }
+            JointKind::Spring(joint) => {
+                let d = (b.pos + rb) - (a.pos + ra);
+                let len = d.length();
+                if !joint.solved() || len <= f32::EPSILON {
+                    return;
+                }
+                let n = d / len;
+                let w = generalized_inv_mass(a, ra, n) + generalized_inv_mass(b, rb, n);
+                let (gamma, rate) = soft(joint.stiffness, joint.damping, time);
+                let cdot = (point_velocity(b, rb) - point_velocity(a, ra)).dot(n);
+                let c = len - joint.rest_length;
+                let impulse = -(cdot + rate * c + gamma * self.impulses[0]) / (w + gamma);
+                self.impulses[0] += impulse;
+                apply_velocity_impulse(a, b, ra, rb, n * impulse);
+            }
+        }
+    }
+
+    pub fn solve_position(&mut self, bodies: &mut [SolverBody], start: &[SolverBody], time: f32) {
+        let (sa, sb) = (&start[self.a], &start[self.b]);
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let lambdas = &mut self.lambdas;
+        match self.kind {
+            JointKind::Distance(joint) => {
+                let mut correct =
+                    |target: &dyn Fn(f32) -> Option<f32>, compliance, lambda: &mut f32| {
+                        let (ra, rb) = (rotated(a, sa, self.ra), rotated(b, sb, self.rb));
+                        correct_distance(a, b, ra, rb, target, compliance, lambda, time);
+                    };
+                if let Some(compliance) = joint.compliance {
+                    correct(&|_| Some(joint.length), compliance, &mut lambdas[0]);
+                } else if joint.min_length >= joint.max_length {
+                    correct(&|_| Some(joint.min_length), 0., &mut lambdas[0]);
+                }
+                if joint.min_length < joint.max_length {
+                    let min = |len| (len < joint.min_length).then_some(joint.min_length);
+                    correct(&min, 0., &mut lambdas[1]);
+                    let max = |len| (len > joint.max_length).then_some(joint.max_length);
+                    correct(&max, 0., &mut lambdas[2]);
+                }
+            }
+            JointKind::Revolute(joint) => {
+                let w = a.inv_inertia + b.inv_inertia;
+                if w > 0. {
+                    // The motor turns the bodies by the angle the target speed
+                    // adds to the one they already had this step.
+                    if joint.max_motor_torque > 0. {
+                        let turned = (a.rot - sa.rot) - (b.rot - sb.rot);
+                        let c = (joint.motor_speed - (sa.rot_vel - sb.rot_vel)) * time - turned;
+                        let max = joint.max_motor_torque * time * time;
+                        let old = lambdas[2];
+                        lambdas[2] = (old + delta_lambda(c, w, old, 0., time)).clamp(-max, max);
+                        apply_angular_position_impulse(b, a, lambdas[2] - old);
+                    }
+
+                    let angle = a.rot - b.rot - joint.reference_angle;
+                    if angle < joint.min_angle {
+                        let dl = delta_lambda(joint.min_angle - angle, w, lambdas[3], 0., time);
+                        lambdas[3] += dl;
+                        apply_angular_position_impulse(b, a, dl);
+                    } else if angle > joint.max_angle {
+                        let dl = delta_lambda(angle - joint.max_angle, w, lambdas[4], 0., time);
+                        lambdas[4] += dl;
+                        apply_angular_position_impulse(b, a, -dl);
+                    }
+                }
+
+                let (ra, rb) = (rotated(a, sa, self.ra), rotated(b, sb, self.rb));
+                let mut lambda = Vec2::new(lambdas[0], lambdas[1]);
+                correct_point(a, b, ra, rb, 0., &mut lambda, time);
+                (lambdas[0], lambdas[1]) = (lambda.x, lambda.y);
+            }
+            JointKind::Prismatic(joint) => {
+                let offset = |a: &SolverBody, b: &SolverBody| {
+                    (a.pos + rotated(a, sa, self.ra)) - (b.pos + rotated(b, sb, self.rb))
+                };
+                // Corrections act at the anchor of `a` like in the velocity solver.
+                let arms = |a: &SolverBody, b: &SolverBody, n: Vec2| {
+                    let ra = rotated(a, sa, self.ra);
+                    let arm = a.pos + ra - b.pos;
+                    let w = generalized_inv_mass(a, ra, n) + generalized_inv_mass(b, arm, n);
+                    (ra, arm, w)
+                };
+
+                // Like the revolute motor, the motor slides the bodies by the
+                // distance the target speed adds to the one they already had.
+                if joint.max_motor_force > 0. {
+                    let axis = joint.world_axis(b);
+                    let start_axis = joint.world_axis(sb);
+                    let slid = axis.dot(offset(a, b)) - start_axis.dot(offset(sa, sb));
+                    let arm = sa.pos + self.ra - sb.pos;
+                    let speed =
+                        (point_velocity(sa, self.ra) - point_velocity(sb, arm)).dot(start_axis);
+                    let c = (joint.motor_speed - speed) * time - slid;
+                    let (ra, arm, w) = arms(a, b, axis);
+                    let max = joint.max_motor_force * time * time;
+                    let old = lambdas[2];
+                    lambdas[2] = (old + delta_lambda(c, w, old, 0., time)).clamp(-max, max);
+                    apply_position_impulse(a, b, ra, arm, -axis * (lambdas[2] - old));
+                }
+
+                let axis = joint.world_axis(b);
+                let translation = axis.dot(offset(a, b));
+                let (ra, arm, w) = arms(a, b, axis);
+                if translation < joint.min_translation {
+                    let c = joint.min_translation - translation;
+                    let dl = delta_lambda(c, w, lambdas[3], 0., time);
+                    lambdas[3] += dl;
+                    apply_position_impulse(a, b, ra, arm, -axis * dl);
+                } else if translation > joint.max_translation {
+                    let c = translation - joint.max_translation;
+                    let dl = delta_lambda(c, w, lambdas[4], 0., time);
+                    lambdas[4] += dl;
+                    apply_position_impulse(a, b, ra, arm, axis * dl);
+                }
+
+                let angle = b.rot - a.rot - joint.reference_angle;
+                let w = a.inv_inertia + b.inv_inertia;
+                let dl = delta_lambda(-angle, w, lambdas[1], 0., time);
+                lambdas[1] += dl;
+                apply_angular_position_impulse(a, b, dl);
+
+                let perp = joint.world_axis(b).perp();
+                let (ra, arm, w) = arms(a, b, perp);
+                let dl = delta_lambda(-perp.dot(offset(a, b)), w, lambdas[0], 0., time);
+                lambdas[0] += dl;
+                apply_position_impulse(a, b, ra, arm, -perp * dl);
+            }
+            JointKind::Weld(joint) => {
+                let angle = b.rot - a.rot - joint.reference_angle;
+                let w = a.inv_inertia + b.inv_inertia;
+                let dl = delta_lambda(-angle, w, lambdas[2], joint.angular_compliance, time);
+                lambdas[2] += dl;
+                apply_angular_position_impulse(a, b, dl);
+
+                let (ra, rb) = (rotated(a, sa, self.ra), rotated(b, sb, self.rb));
+                let mut lambda = Vec2::new(lambdas[0], lambdas[1]);
+                correct_point(a, b, ra, rb, joint.linear_compliance, &mut lambda, time);
+                (lambdas[0], lambdas[1]) = (lambda.x, lambda.y);
+            }
+            // Damping is applied to the velocities in `damp`.
+            JointKind::Spring(joint) => {
+                if joint.solved() && joint.stiffness > 0. {
+                    let (ra, rb) = (rotated(a, sa, self.ra), rotated(b, sb, self.rb));
+                    let target = |_| Some(joint.rest_length);
+                    let compliance = 1. / joint.stiffness;
+                    correct_distance(a, b, ra, rb, &target, compliance, &mut lambdas[0], time);
+                }
+            }
+        }
+    }
+
+    // Turns the multipliers of the XPBD backend into the impulses they applied.
+    pub fn store_lambdas(&mut self, time: f32) {
+        self.impulses = self.lambdas.map(|lambda| lambda / time);
+        match self.kind {
+            JointKind::Distance(_) => self.impulses[2] = -self.impulses[2],
+            JointKind::Revolute(_)
+            | JointKind::Prismatic(_)
+            | JointKind::Weld(_)
+            | JointKind::Spring(_) => {}
+        }
+    }
+
+    // Velocity pass of the XPBD backend, removes the relative speed along a
+    // soft spring its damping would absorb during the step.
+    pub fn damp(&mut self, bodies: &mut [SolverBody], start: &[SolverBody], time: f32) {
+        let JointKind::Spring(joint) = self.kind else {
+            return;
+        };
+        if !joint.solved() || joint.damping <= 0. {
+            return;
+        }
+        let (sa, sb) = (&start[self.a], &start[self.b]);
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let (ra, rb) = (rotated(a, sa, self.ra), rotated(b, sb, self.rb));
+        let n = ((b.pos + rb) - (a.pos + ra)).normalize_or_zero();
+        let w = generalized_inv_mass(a, ra, n) + generalized_inv_mass(b, rb, n);
+        if w <= 0. {
+            return;
+        }
+        let speed = (point_velocity(b, rb) - point_velocity(a, ra)).dot(n);
+        let impulse = -speed * f32::min(joint.damping * time, 1. / w);
+        self.impulses[0] += impulse;
+        apply_velocity_impulse(a, b, ra, rb, n * impulse);
+    }
+
+    // Whether the impulses applied during a step of `time` went past the
+    // thresholds of a breakable joint.
+    pub fn broken(&self, time: f32) -> bool {
+        match self.kind {
+            JointKind::Weld(joint) if time > 0. => {
+                let force = Vec2::new(self.impulses[0], self.impulses[1]).length() / time;
+                let torque = self.impulses[2].abs() / time;
+                force > joint.break_force || torque > joint.break_torque
+            }
+            _ => false,
+        }
+    }
+}
+
+// Pushes the bodies joined by springs in `SpringMode::Force` with the impulse of
+// the spring force over the substep, before `physics::run` integrates them.
+// Forces applied to `Shape` last the whole tick, so they can't be used here.
+pub fn apply_spring_forces(
+    joints: Query<&Joint>,
+    mut query: Query<(&mut Shape, Has<Sleeping>)>,
+    step: Res<SubstepTime>,
+) {
+    for joint in &joints {
+        let JointKind::Spring(spring) = joint.kind else {
+            continue;
+        };
+        if spring.mode != SpringMode::Force {
+            continue;
+        }
+        let anchor = |shape: &Shape, local: Vec2| {
+            let point = shape.world_point(local);
+            let r = point - shape.world_center_of_mass();
+            (point, shape.vel + r.perp() * shape.rot_vel)
+        };
+        let Ok((a, _)) = query.get(joint.a) else {
+            continue;
+        };
+        let (pa, va) = anchor(a, joint.anchor_a);
+        let (pb, vb) = match joint.b {
+            Some(b) => match query.get(b) {
+                Ok((b, _)) => anchor(b, joint.anchor_b),
+                Err(_) => continue,
+            },
+            None => (joint.anchor_b, Vec2::ZERO),
+        };
+        let d = pb - pa;
+        let len = d.length();
+        if len <= f32::EPSILON {
+            continue;
+        }
+        let n = d / len;
+        let force = n * spring.force(len, (vb - va).dot(n));
+        for (entity, point, force) in [(Some(joint.a), pa, -force), (joint.b, pb, force)] {
+            let Some(Ok((mut shape, sleeping))) = entity.map(|e| query.get_mut(e)) else {
+                continue;
+            };
+            if shape.body.is_dynamic() && !sleeping {
+                shape.apply_impulse_at_point(force * step.0, point);
+            }
+        }
+    }
+}
+
+// Moves the anchors along the line joining them until their distance is the
+// one returned by `target`, if any.
+#[allow(clippy::too_many_arguments)]
+fn correct_distance(
+    a: &mut SolverBody,
+    b: &mut SolverBody,
+    ra: Vec2,
+    rb: Vec2,
+    target: &dyn Fn(f32) -> Option<f32>,
+    compliance: f32,
+    lambda: &mut f32,
+    time: f32,
+) {
+    let d = (b.pos + rb) - (a.pos + ra);
+    let len = d.length();
+    let Some(target) = target(len) else {
+        return;
+    };
+    if len <= f32::EPSILON {
+        return;
+    }
+    let n = d / len;
+    let w = generalized_inv_mass(a, ra, n) + generalized_inv_mass(b, rb, n);
+    let dl = delta_lambda(target - len, w, *lambda, compliance, time);
+    *lambda += dl;
+    apply_position_impulse(a, b, ra, rb, n * dl);
+}
+
+// Moves the anchors onto each other, `lambda` accumulates the position impulse.
+fn correct_point(
+    a: &mut SolverBody,
+    b: &mut SolverBody,
+    ra: Vec2,
+    rb: Vec2,
+    compliance: f32,
+    lambda: &mut Vec2,
+    time: f32,
+) {
+    let d = (b.pos + rb) - (a.pos + ra);
+    let len = d.length();
+    if len <= f32::EPSILON {
+        return;
+    }
+    let n = d / len;
+    let w = generalized_inv_mass(a, ra, n) + generalized_inv_mass(b, rb, n);
+    let dl = delta_lambda(-len, w, lambda.dot(n), compliance, time);
+    *lambda += n * dl;
+    apply_position_impulse(a, b, ra, rb, n * dl);
+}
+
+// Solves both axes of the constraint keeping the anchors together at once.
+// `softness` is the pair returned by `soft`, a rigid constraint passes no gamma
+// and the Baumgarte rate. The first two `impulses` accumulate the result.
+fn solve_point(
+    a: &mut SolverBody,
+    b: &mut SolverBody,
+    ra: Vec2,
+    rb: Vec2,
+    (gamma, rate): (f32, f32),
+    impulses: &mut [f32],
+) {
+    let k = point_mass(a, b, ra, rb) + Mat2::from_diagonal(Vec2::splat(gamma));
+    if k.determinant().abs() <= f32::EPSILON {
+        return;
+    }
+    let accumulated = Vec2::new(impulses[0], impulses[1]);
+    let c = (b.pos + rb) - (a.pos + ra);
+    let cdot = point_velocity(b, rb) - point_velocity(a, ra);
+    let impulse = -(k.inverse() * (cdot + rate * c + gamma * accumulated));
+    impulses[0] += impulse.x;
+    impulses[1] += impulse.y;
+    apply_velocity_impulse(a, b, ra, rb, impulse);
+}
+
+// Mass matrix seen by an impulse applied at the anchors, inverted to solve
+// both axes of a point constraint at once.
+fn point_mass(a: &SolverBody, b: &SolverBody, ra: Vec2, rb: Vec2) -> Mat2 {
+    let (ia, ib) = (a.inv_inertia, b.inv_inertia);
+    let m = a.inv_mass + b.inv_mass;
+    let xy = -ia * ra.x * ra.y - ib * rb.x * rb.y;
+    Mat2::from_cols(
+        Vec2::new(m.x + ia * ra.y * ra.y + ib * rb.y * rb.y, xy),
+        Vec2::new(xy, m.y + ia * ra.x * ra.x + ib * rb.x * rb.x),
+    )
+}
+
+// Inverse of the softened effective mass and the bias rate of a spring with
+// `stiffness` and `damping`, see Catto's "Soft Constraints".
+pub fn soft(stiffness: f32, damping: f32, time: f32) -> (f32, f32) {
+    let d = damping + time * stiffness;
+    if d <= 0. {
+        return (0., 0.);
+    }
+    (1. / (time * d), stiffness / d)
+}
+
+// Limits only push, they let `c` reach 0 this step before correcting any error
+// past it. Returns the change of the impulse accumulated in `impulse`.
+fn limit_impulse(
+    impulse: &mut f32,
+    c: f32,
+    cdot: f32,
+    w: f32,
+    settings: &SolverSettings,
+    time: f32,
+) -> f32 {
+    let bias = if c > 0. {
+        c / time
+    } else {
+        settings.correction_factor * c / time
+    };
+    let old = *impulse;
+    *impulse = (old - (cdot + bias) / w).max(0.);
+    *impulse - old
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::{Bodys, RigidBody};
+    use crate::physics::tests::{spawn, test_app};
+    use crate::solver::SolverBackend;
+
+    const BACKENDS: [SolverBackend; 2] = [SolverBackend::Impulse, SolverBackend::Xpbd];
+
+    fn joint_app(backend: SolverBackend) -> App {
+        let mut app = test_app();
+        app.world.resource_mut::<SolverSettings>().backend = backend;
+        app
+    }
+
+    fn run(app: &mut App, ticks: usize) {
+        for _ in 0..ticks {
+            app.world.run_schedule(FixedUpdate);
+        }
+    }
+
+    #[test]
+    fn compliant_rod_stretches_under_its_load() {
+        for backend in BACKENDS {
+            let mut app = joint_app(backend);
+            let anchor = Vec2::new(0., 300.);
+            let pos = Vec2::new(0., 180.);
+            let bob = spawn(&mut app, Bodys::Circle(10.), pos, RigidBody::Dynamic);
+            let weight = app.world.get::<Shape>(bob).unwrap().mass * 196.;
+            let mut rod = DistanceJoint::new(100.);
+            rod.compliance = Some(20. / weight);
+            let kind = JointKind::Distance(rod);
+            app.world
+                .spawn(Joint::new(kind, bob, None, Vec2::ZERO, anchor));
+            run(&mut app, 200);
+
+            let stretch = anchor.distance(app.world.get::<Shape>(bob).unwrap().pos) - 100.;
+            assert!(
+                (stretch - 20.).abs() < 2.,
+                "{backend:?} stretched {stretch}"
+            );
+        }
+    }
+
+    #[test]
+    fn revolute_motor_and_limit_follow_the_body() {
+        for backend in BACKENDS {
+            let mut app = joint_app(backend);
+            let pos = Vec2::new(0., 300.);
+            let wheel = spawn(&mut app, Bodys::Circle(20.), pos, RigidBody::Dynamic);
+            let mut motor = RevoluteJoint::new();
+            motor.motor_speed = 2.;
+            motor.max_motor_torque = 1e9;
+            let kind = JointKind::Revolute(motor);
+            app.world
+                .spawn(Joint::new(kind, wheel, None, Vec2::ZERO, pos));
+
+            // Hinged at its left end, gravity turns it clockwise onto the limit.
+            let pos = Vec2::new(250., 300.);
+            let bar = spawn(&mut app, Bodys::Rec(100., 10.), pos, RigidBody::Dynamic);
+            let mut hinge = RevoluteJoint::new();
+            hinge.min_angle = -0.5;
+            let (anchor, hinge_point) = (Vec2::new(-50., 0.), Vec2::new(200., 300.));
+            let kind = JointKind::Revolute(hinge);
+            app.world
+                .spawn(Joint::new(kind, bar, None, anchor, hinge_point));
+            run(&mut app, 200);
+
+            let wheel = app.world.get::<Shape>(wheel).unwrap();
+            assert!(
+                (wheel.rot_vel - 2.).abs() < 0.05,
+                "{backend:?} spins at {}",
+                wheel.rot_vel
+            );
+            let bar = app.world.get::<Shape>(bar).unwrap();
+            assert!(
+                (bar.rot + 0.5).abs() < 0.05,
+                "{backend:?} turned to {}",
+                bar.rot
+            );
+            assert!(bar.world_point(anchor).distance(hinge_point) < 1.);
+        }
+    }
+
+    #[test]
+    fn pendulum_keeps_its_length() {
+        for backend in BACKENDS {
+            let mut app = joint_app(backend);
+            let anchor = Vec2::new(0., 300.);
+            let pos = Vec2::new(100., 300.);
+            let bob = spawn(&mut app, Bodys::Circle(10.), pos, RigidBody::Dynamic);
+            let kind = JointKind::Distance(DistanceJoint::new(100.));
+            app.world
+                .spawn(Joint::new(kind, bob, None, Vec2::ZERO, anchor));
+
+            let mut error: f32 = 0.;
+            for _ in 0..300 {
+                run(&mut app, 1);
+                let pos = app.world.get::<Shape>(bob).unwrap().pos;
+                error = error.max((anchor.distance(pos) - 100.).abs());
+            }
+            assert!(error < 1., "{backend:?} drifted {error} from the length");
+        }
+    }
+
+    #[test]
+    fn prismatic_slides_along_its_axis_onto_the_limit() {
+        for backend in BACKENDS {
+            let mut app = joint_app(backend);
+            let (pos, axis) = (Vec2::new(0., 300.), Vec2::new(1., 1.).normalize());
+            let slider = spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Dynamic);
+            let mut rail = PrismaticJoint::new(axis);
+            rail.min_translation = -50.;
+            let kind = JointKind::Prismatic(rail);
+            app.world
+                .spawn(Joint::new(kind, slider, None, Vec2::ZERO, pos));
+            run(&mut app, 200);
+
+            let slider = app.world.get::<Shape>(slider).unwrap();
+            let offset = slider.pos - pos;
+            let (along, across) = (offset.dot(axis), offset.dot(axis.perp()));
+            assert!((along + 50.).abs() < 1., "{backend:?} slid to {along}");
+            assert!(across.abs() < 1., "{backend:?} left the axis by {across}");
+            assert!(
+                slider.rot.abs() < 0.01,
+                "{backend:?} turned to {}",
+                slider.rot
+            );
+        }
+    }
+
+    #[test]
+    fn weld_breaks_past_its_force_and_reports_it() {
+        for backend in BACKENDS {
+            let mut app = joint_app(backend);
+            let mut welds = vec![];
+            // Only the first weld is too weak to hold the weight of its body.
+            for (x, strength) in [(-100., 0.5), (100., 4.)] {
+                let pos = Vec2::new(x, 300.);
+                let block = spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Dynamic);
+                let mut weld = WeldJoint::new();
+                weld.break_force = app.world.get::<Shape>(block).unwrap().mass * 196. * strength;
+                let kind = JointKind::Weld(weld);
+                let joint = Joint::new(kind, block, None, Vec2::ZERO, pos);
+                welds.push((app.world.spawn(joint).id(), block));
+            }
+            run(&mut app, 60);
+
+            let [(weak, dropped), (strong, held)] = welds[..] else {
+                unreachable!()
+            };
+            assert!(app.world.get_entity(weak).is_none(), "{backend:?}");
+            assert!(app.world.get_entity(strong).is_some(), "{backend:?}");
+            let events = app.world.resource::<Events<JointBroken>>();
+            let broken: Vec<_> = events.get_reader().read(events).copied().collect();
+            assert_eq!(broken.len(), 1, "{backend:?}");
+            assert_eq!((broken[0].joint, broken[0].a), (weak, dropped));
+            assert_eq!(broken[0].b, None);
+            assert!(app.world.get::<Shape>(dropped).unwrap().pos.y < 250.);
+            assert!(app.world.get::<Shape>(held).unwrap().pos.y > 299.);
+        }
+    }
+
+    #[test]
+    fn spring_settles_at_its_load() {
+        for (backend, mode) in BACKENDS
+            .into_iter()
+            .flat_map(|backend| [(backend, SpringMode::Soft), (backend, SpringMode::Force)])
+        {
+            let mut app = joint_app(backend);
+            let anchor = Vec2::new(0., 300.);
+            let pos = Vec2::new(0., 250.);
+            let bob = spawn(&mut app, Bodys::Circle(10.), pos, RigidBody::Dynamic);
+            let mass = app.world.get::<Shape>(bob).unwrap().mass;
+            let mut spring = SpringJoint::new(50., mass * 20., mass * 4.);
+            spring.mode = mode;
+            let kind = JointKind::Spring(spring);
+            app.world
+                .spawn(Joint::new(kind, bob, None, Vec2::ZERO, anchor));
+            run(&mut app, 600);
+
+            // m * g / k with a gravity of 196.
+            let stretch = anchor.distance(app.world.get::<Shape>(bob).unwrap().pos) - 50.;
+            assert!(
+                (stretch - 9.8).abs() < 0.5,
+                "{backend:?} {mode:?} stretched {stretch}"
+            );
+        }
+    }
+}
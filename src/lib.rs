@@ -1,5 +1,21 @@
 pub mod body;
 
+pub mod broadphase;
+
 pub mod collisions;
 
-pub mod physics;
\ No newline at end of file
+pub mod determinism;
+
+pub mod integrator;
+
+pub mod islands;
+
+pub mod joints;
+
+pub mod physics;
+
+pub mod snapshot;
+
+pub mod solver;
+
+pub mod xpbd;
\ No newline at end of file
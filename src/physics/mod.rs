@@ -1,9 +1,23 @@
-use bevy::{prelude::*, window::close_on_esc};
+use std::time::Duration;
+
+use bevy::{
+    ecs::{schedule::ScheduleLabel, system::SystemParam},
+    prelude::*,
+    utils::HashSet,
+    window::close_on_esc,
+};
 use bevy_pancam::{PanCam, PanCamPlugin};
 
 use super::body::*;
+use super::broadphase::{update_broadphase, PhysicsBroadphase};
+use super::collisions::narrowphase;
+use super::determinism::{update_checksum, Determinism, WorldChecksum};
+use super::integrator::{ForceFields, Gravity, Integrator, SubstepTime};
+use super::islands::{update_sleep, wake_bodies, wake_island, Islands, SleepSettings, Sleeping};
+use super::joints::{apply_spring_forces, Joint, JointBroken, JointConstraint};
+use super::solver::{build_islands, solve_islands, ContactCache, SolverBody, SolverSettings};
 
-use rand::{thread_rng, Rng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 const COLORS: [Color; 8] = [
     Color::RED,
@@ -15,25 +29,211 @@ const COLORS: [Color; 8] = [
     Color::AZURE,
     Color::YELLOW,
 ];
-const ITERATIONS: usize = 1;
 
 pub struct PhsyicsEngine;
 
+const MIN_TIME_SCALE: f32 = 0.1;
+const MAX_TIME_SCALE: f32 = 4.;
+
+// Default damping of the bodies that don't set their own.
+#[derive(Resource, Default)]
+pub struct Damping {
+    pub linear: f32,
+    pub angular: f32,
+}
+
+// Random numbers of the demo, seeded from `Determinism` when it is enabled so
+// runs spawn the same bodies.
+#[derive(Resource)]
+pub struct DemoRng(pub StdRng);
+
+impl FromWorld for DemoRng {
+    fn from_world(world: &mut World) -> Self {
+        match world.get_resource::<Determinism>() {
+            Some(determinism) if determinism.enabled => {
+                Self(StdRng::seed_from_u64(determinism.seed))
+            }
+            _ => Self(StdRng::from_entropy()),
+        }
+    }
+}
+
+// Schedule holding one simulation step, run `Substeps` times every fixed tick.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PhysicsSubstep;
+
+#[derive(Resource)]
+pub struct Substeps(pub u32);
+
+impl std::default::Default for Substeps {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+// Controls the fixed tick of the simulation. `timestep` is copied into
+// `Time<Fixed>`, while `time_scale` only scales the time simulated per tick.
+// Ticks of zero length are skipped.
+#[derive(Resource)]
+pub struct PhysicsTime {
+    pub timestep: Duration,
+    time_scale: f32,
+    pub paused: bool,
+    step: bool,
+}
+
+impl std::default::Default for PhysicsTime {
+    fn default() -> Self {
+        Self {
+            timestep: Duration::from_secs_f64(1. / 64.),
+            time_scale: 1.,
+            paused: false,
+            step: false,
+        }
+    }
+}
+
+impl PhysicsTime {
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    // Runs a single tick on the next fixed update while paused, ignored while running.
+    pub fn step(&mut self) {
+        self.step = self.paused;
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE);
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        (self.timestep.as_secs_f64() * self.time_scale as f64) as f32
+    }
+
+    fn consume_tick(&mut self) -> bool {
+        std::mem::take(&mut self.step) || !self.paused
+    }
+}
+
 impl Plugin for PhsyicsEngine{
     fn build(&self, app: &mut App) {
         app.add_plugins(DefaultPlugins);
         app.add_plugins(PanCamPlugin);
         app.insert_resource(ClearColor(Color::rgb_u8(11, 187, 202)));
-        app.add_systems(Update, close_on_esc);
+        app.add_systems(Update, (close_on_esc, time_controls));
+        add_simulation(app);
+        app.init_resource::<DemoRng>();
         app.add_systems(Startup, setup);
-        app.add_systems(FixedUpdate, (run, collisions, draw).chain());
+        app.add_systems(FixedUpdate, draw.after(run_substeps));
         app.add_systems(FixedUpdate, (moving, creating));
     }
 }
 
+// Resources, events and systems of the simulation itself, without the window
+// and the demo.
+fn add_simulation(app: &mut App) {
+    app.init_resource::<Gravity>();
+    app.init_resource::<Damping>();
+    app.init_resource::<Integrator>();
+    app.init_resource::<ForceFields>();
+    app.init_resource::<PhysicsBroadphase>();
+    app.init_resource::<Islands>();
+    app.init_resource::<SleepSettings>();
+    app.init_resource::<SolverSettings>();
+    app.init_resource::<ContactCache>();
+    app.init_resource::<Substeps>();
+    app.init_resource::<SubstepTime>();
+    app.init_resource::<PhysicsTime>();
+    app.init_resource::<Determinism>();
+    app.init_resource::<WorldChecksum>();
+    app.add_event::<JointBroken>();
+    app.add_systems(
+        PhysicsSubstep,
+        (
+            wake_bodies,
+            apply_spring_forces,
+            run,
+            update_broadphase,
+            collisions,
+            update_sleep,
+            update_checksum,
+        )
+            .chain(),
+    );
+    app.add_systems(PreUpdate, sync_timestep);
+    app.add_systems(FixedUpdate, run_substeps);
+}
+
+fn sync_timestep(physics_time: Res<PhysicsTime>, mut fixed_time: ResMut<Time<Fixed>>) {
+    if physics_time.timestep != fixed_time.timestep() && !physics_time.timestep.is_zero() {
+        fixed_time.set_timestep(physics_time.timestep);
+    }
+}
+
+// Space pauses, `.` steps a single tick while paused, `-` and `=` slow down and
+// speed up the simulation and `0` goes back to real time.
+fn time_controls(mut physics_time: ResMut<PhysicsTime>, key: Res<ButtonInput<KeyCode>>) {
+    if key.just_pressed(KeyCode::Space) {
+        physics_time.toggle_pause();
+    }
+    if key.just_pressed(KeyCode::Period) {
+        physics_time.step();
+    }
+    if key.just_pressed(KeyCode::Minus) {
+        let time_scale = physics_time.time_scale() / 2.;
+        physics_time.set_time_scale(time_scale);
+    }
+    if key.just_pressed(KeyCode::Equal) {
+        let time_scale = physics_time.time_scale() * 2.;
+        physics_time.set_time_scale(time_scale);
+    }
+    if key.just_pressed(KeyCode::Digit0) {
+        physics_time.set_time_scale(1.);
+    }
+}
+
+fn run_substeps(world: &mut World) {
+    let mut physics_time = world.resource_mut::<PhysicsTime>();
+    if !physics_time.consume_tick() {
+        return;
+    }
+    let time = physics_time.delta_seconds();
+    if time <= 0. {
+        return;
+    }
+    let substeps = world.resource::<Substeps>().0.max(1);
+    world.resource_mut::<SubstepTime>().0 = time / substeps as f32;
+    for _ in 0..substeps {
+        world.run_schedule(PhysicsSubstep);
+    }
+
+    // Every substep integrates the forces applied before the tick.
+    let mut query = world.query::<&mut Shape>();
+    for mut shape in query.iter_mut(world) {
+        if shape.force != Vec2::ZERO || shape.torque != 0. {
+            shape.force = Vec2::ZERO;
+            shape.torque = 0.;
+        }
+    }
+}
+
 fn draw(mut query: Query<(&Shape, &mut Transform)>,){
     for (ent,mut  transform) in query.iter_mut(){
         transform.translation = Vec3::new(ent.pos.x, ent.pos.y, 0.);
+        transform.rotation = Quat::from_rotation_z(ent.rot);
     }
 }
 
@@ -43,8 +243,9 @@ fn draw_vecs(gizmos: &mut Gizmos, pos: Vec2, vec: Vec2, color: Color) {
 
 fn moving(mut query: Query<&mut Shape>, key: Res<ButtonInput<KeyCode>>) {
     for mut i in query.iter_mut() {
-        if i.movil {
+        if i.body.is_kinematic() {
             i.vel = Vec2::ZERO;
+            i.rot_vel = 0.;
             if key.pressed(KeyCode::ArrowLeft) {
                 i.vel.x = -250.;
             }
@@ -58,28 +259,41 @@ fn moving(mut query: Query<&mut Shape>, key: Res<ButtonInput<KeyCode>>) {
                 i.vel.y = -250.;
             }
             if key.pressed(KeyCode::KeyA) {
-                i.rot += f32::to_radians(2.);
+                i.rot_vel = f32::to_radians(128.);
             }
             if key.pressed(KeyCode::KeyD) {
-                i.rot -= f32::to_radians(2.);
+                i.rot_vel = -f32::to_radians(128.);
             }
         }
     }
 }
 
+// Window and camera read by `creating` to place bodies under the cursor.
+#[derive(SystemParam)]
+struct CursorParams<'w, 's> {
+    window: Query<'w, 's, &'static Window>,
+    camera_transform: Query<'w, 's, &'static Transform, With<Camera>>,
+    query_camera: Query<'w, 's, &'static OrthographicProjection>,
+}
+
 fn creating(
     query: Query<&Transform>,
     input: Res<ButtonInput<MouseButton>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
     mut commands: Commands,
-    window: Query<&Window>,
-    camera_transform: Query<&Transform, With<Camera>>,
-    query_camera: Query<&OrthographicProjection>,
+    cursor: CursorParams,
+    mut rng: ResMut<DemoRng>,
 ) {
+    let CursorParams {
+        window,
+        camera_transform,
+        query_camera,
+    } = &cursor;
+    let rng = &mut rng.0;
     let window = window.single();
     let camera = query_camera.single();
-    let res = || {
+    let res = |rng: &mut StdRng| {
         let camera_pos = camera_transform
             .get_single()
             .unwrap()
@@ -90,13 +304,13 @@ fn creating(
         let pos = window.cursor_position().unwrap() * camera.scale;
         let pos =
             Vec2::new((window_size / 2.).x + pos.x, (window_size / 2.).y - pos.y) + camera_pos;
-        let w = thread_rng().gen_range(10..=50) as f32;
-        let h = thread_rng().gen_range(10..=50) as f32;
+        let w = rng.gen_range(10..=50) as f32;
+        let h = rng.gen_range(10..=50) as f32;
         (pos, w, h)
     };
     if input.just_pressed(MouseButton::Left) {
-        let (pos, r, _) = res();
-        let color = COLORS[thread_rng().gen_range(0..=7)];
+        let (pos, r, _) = res(rng);
+        let color = COLORS[rng.gen_range(0..=7)];
         create_body(
             &mut commands,
             &mut meshes,
@@ -104,14 +318,13 @@ fn creating(
             Bodys::Circle(r),
             pos,
             color,
-            false,
-            false,
+            RigidBody::Dynamic,
         );
     }
     if input.just_pressed(MouseButton::Right) {
-        let (pos, w, h) = res();
-        let color = COLORS[thread_rng().gen_range(0..=7)];
-        if thread_rng().gen_bool(0.5) {
+        let (pos, w, h) = res(rng);
+        let color = COLORS[rng.gen_range(0..=7)];
+        if rng.gen_bool(0.5) {
             create_body(
                 &mut commands,
                 &mut meshes,
@@ -119,11 +332,10 @@ fn creating(
                 Bodys::Rec(w, h),
                 pos,
                 color,
-                false,
-                false,
+                RigidBody::Dynamic,
             );
         }else{
-            let r = thread_rng().gen_range(3..=10);
+            let r = rng.gen_range(3..=10);
             create_body(
                 &mut commands,
                 &mut meshes,
@@ -131,37 +343,54 @@ fn creating(
                 Bodys::Poly(w, r),
                 pos,
                 color,
-                false,
-                false,
+                RigidBody::Dynamic,
             );
         }
     }
 }
 
 fn run(
-    mut query: Query<(&mut Shape, &mut Transform)>,
-    fixed_time: Res<Time<Fixed>>,
+    mut query: Query<&mut Shape, Without<Sleeping>>,
+    step: Res<SubstepTime>,
+    gravity: Res<Gravity>,
+    damping: Res<Damping>,
+    integrator: Res<Integrator>,
+    fields: Res<ForceFields>,
     mut gizmos: Gizmos,
 ) {
-    let time = fixed_time.timestep().as_millis() as f32 / 1000.;
-
-    for (mut ent, mut transform) in query.iter_mut() {
-        if !ent.is_static {
-            let mass = ent.mass;
-            let vel_rot = ent.rot_vel;
-            ent.rot += vel_rot * time;
-            let force = ent.force;
-            ent.ac += force / mass * time;
-            let ac = ent.ac;
-            ent.vel += ac * time;
-            let vel = ent.vel;
-            ent.pos += vel * time;
-            ent.force = Vec2::ZERO;
+    let time = step.0;
+
+    for mut ent in query.iter_mut() {
+        if ent.body.is_kinematic() {
+            let center = ent.world_center_of_mass() + ent.vel * time;
+            ent.rot += ent.rot_vel * time;
+            ent.place_center_of_mass(center);
+        } else if ent.body.is_dynamic() {
+            let ac = gravity.0 * ent.gravity_scale + ent.force * ent.inv_mass;
+            let center = ent.world_center_of_mass();
+            let (mut pos, mut vel) = (center, ent.vel);
+            integrator.step(&mut pos, &mut vel, time, |pos, vel| {
+                ac + fields.acceleration(pos, vel)
+            });
+            let free = ent.locks.linear_mask();
+            let center = center + (pos - center) * free;
+            ent.vel = vel;
+
+            let rot_ac = ent.torque * ent.inv_inertia;
+            let (mut rot, mut rot_vel) = (ent.rot, ent.rot_vel);
+            integrator.step_angular(&mut rot, &mut rot_vel, time, rot_ac);
+            if !ent.locks.rotation {
+                (ent.rot, ent.rot_vel) = (rot, rot_vel);
+            }
+            ent.place_center_of_mass(center);
+
+            let linear_damping = ent.linear_damping.unwrap_or(damping.linear);
+            let angular_damping = ent.angular_damping.unwrap_or(damping.angular);
+            ent.vel *= 1. / (1. + time * linear_damping);
+            ent.rot_vel *= 1. / (1. + time * angular_damping);
+            ent.limit_velocity();
         }
-        let rot = ent.rot;
-        ent.rotate(rot);
-        transform.rotate_z(rot);
-        ent.rot = 0.;
+        ent.update_pose();
 
         if ent.vec {
             draw_vecs(&mut gizmos, ent.pos, Vec2::new(ent.pos.x, 0.), Color::GREEN);
@@ -170,149 +399,127 @@ fn run(
     }
 }
 
-fn collisions(mut query: Query<&mut Shape>, mut gizmos: Gizmos) {
-    let mut combinations = query.iter_combinations_mut();
-    while let Some([mut a, mut b]) = combinations.fetch_next() {
-        let mut normal = Vec2::ZERO;
-        for _ in 0..ITERATIONS {
-            if a.is_static && b.is_static {
-                continue;
-            }
-            a.aabb = a.get_aabb();
-            b.aabb = b.get_aabb();
-            if a.checkaabb(&b) {
-                continue;
-            }
+// Configuration and state of the solver read by `collisions`.
+#[derive(SystemParam)]
+struct SolverParams<'w> {
+    settings: Res<'w, SolverSettings>,
+    cache: ResMut<'w, ContactCache>,
+    step: Res<'w, SubstepTime>,
+    determinism: Res<'w, Determinism>,
+}
 
-            let (norma, distance) = a.collision(b.pos, &b, &mut gizmos);
-            normal = norma;
-            if a.is_static {
-                b.pos += norma * distance;
-            } else if b.is_static {
-                a.pos += -(norma * distance);
-            } else {
-                a.pos += -(norma * distance / 2.);
-                b.pos += norma * distance / 2.;
+fn collisions(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut Shape, Has<Sleeping>)>,
+    broadphase: Res<PhysicsBroadphase>,
+    mut islands: ResMut<Islands>,
+    mut solver: SolverParams,
+    mut joints: Query<(Entity, &mut Joint)>,
+    mut broken: EventWriter<JointBroken>,
+) {
+    let SolverParams {
+        settings,
+        cache,
+        step,
+        determinism,
+    } = &mut solver;
+    // Bodies that can't wake the ones they touch, kinematic bodies only do so
+    // while they move.
+    let resting = |query: &Query<(Entity, &mut Shape, Has<Sleeping>)>, e| {
+        query.get(e).map_or(true, |(_, shape, sleeping)| {
+            let still = shape.vel == Vec2::ZERO && shape.rot_vel == 0.;
+            shape.body.is_static() || (shape.body.is_kinematic() && still) || sleeping
+        })
+    };
+    let mut pairs: Vec<_> = broadphase
+        .0
+        .query_pairs()
+        .into_iter()
+        .filter(|&(a, b)| !(resting(&query, a) && resting(&query, b)))
+        .collect();
+    let connected: HashSet<_> = joints
+        .iter()
+        .filter(|(_, joint)| !joint.collide_connected)
+        .map(|(_, joint)| (joint.a.min(joint.body_b()), joint.a.max(joint.body_b())))
+        .collect();
+    pairs.retain(|&(a, b)| !connected.contains(&(a.min(b), a.max(b))));
+    determinism.order(&mut pairs, |&(a, b)| (a.min(b), a.max(b)));
+    let mut shapes = query.transmute_lens::<&Shape>();
+    let contacts = narrowphase(&pairs, &shapes.query());
+
+    for contact in &contacts {
+        for (sleeper, other) in [(contact.a, contact.b), (contact.b, contact.a)] {
+            if islands.is_sleeping(sleeper) && !resting(&query, other) {
+                wake_island(&mut commands, &mut islands, sleeper);
             }
         }
-        let sf = (a.static_friction + b.static_friction) * 0.5;
-        let df = (a.dinaminc_friction + b.dinaminc_friction) * 0.5;
-        let (contact1, contact2, cc) = a.contactpoint(&b);
-
-        let rel_vel = a.vel - b.vel;
+    }
 
-        if rel_vel.dot(normal) == 0. {
+    let mut joint_list: Vec<_> = joints.iter().collect();
+    determinism.order(&mut joint_list, |(entity, _)| *entity);
+    let mut joint_constraints = vec![];
+    for (entity, joint) in joint_list {
+        let (ea, eb) = (joint.a, joint.body_b());
+        if ea == eb {
+            warn_once!("joint {entity:?} connects {ea:?} to itself and is ignored");
             continue;
         }
-
-        let e = f32::min(a.restitution, b.restitution);
-
-        let mainv = a.inv_mass;
-        let mbinv = b.inv_mass;
-        let iainv = a.inv_inertia;
-        let ibinv = b.inv_inertia;
-
-        let contactl = [contact1, contact2];
-        let mut impulsel = vec![];
-        let mut jl = vec![0., 0.];
-        let mut ral = vec![];
-        let mut rbl = vec![];
-        for (r, i) in contactl.iter().enumerate() {
-            let i = *i;
-            let ra = i - a.pos;
-            let rb = i - b.pos;
-            ral.push(ra);
-            rbl.push(rb);
-            let raper = Vec2::new(-ra.y, ra.x);
-            let rbper = Vec2::new(-rb.y, rb.x);
-
-            let alv_a = raper * a.rot_vel;
-            let alv_b = rbper * b.rot_vel;
-
-            let rel_vel = (b.vel + alv_b) - (a.vel + alv_a);
-
-            let contacvel = rel_vel.dot(normal);
-
-            if contacvel > 0. {
-                continue;
+        if resting(&query, ea) && resting(&query, eb) {
+            continue;
+        }
+        for (sleeper, other) in [(ea, eb), (eb, ea)] {
+            if islands.is_sleeping(sleeper) && !resting(&query, other) {
+                wake_island(&mut commands, &mut islands, sleeper);
             }
-
-            let raperdot = raper.dot(normal);
-            let rbperdot = rbper.dot(normal);
-
-            let denom = a.inv_mass
-                + b.inv_mass
-                + (raperdot * raperdot) * a.inv_inertia
-                + (rbperdot * rbperdot) * b.inv_inertia;
-
-            let j = -(1. + e) * contacvel;
-            let j = j / denom;
-            let j = j / cc as f32;
-
-            let impulse = j * normal;
-            impulsel.push(impulse);
-            jl[r] = j;
         }
-        for (i, impulse) in impulsel.iter().enumerate() {
-            let impulse = *impulse;
-            let ra = ral[i];
-            let rb = rbl[i];
-
-            a.vel += -impulse * mainv;
-            a.rot_vel += -ra.perp_dot(impulse) * iainv;
-            b.vel += impulse * mbinv;
-            b.rot_vel += rb.perp_dot(impulse) * ibinv;
+        let Ok((_, a, _)) = query.get(ea) else {
+            continue;
+        };
+        let ra = a.world_point(joint.anchor_a) - a.world_center_of_mass();
+        let rb = match query.get(eb) {
+            Ok((_, b, _)) => b.world_point(joint.anchor_b) - b.world_center_of_mass(),
+            Err(_) if joint.b.is_none() => joint.anchor_b,
+            Err(_) => continue,
+        };
+        joint_constraints.push(JointConstraint::new(entity, joint, ra, rb));
+    }
+
+    let mut solver_islands = build_islands(contacts, joint_constraints, |e| {
+        query
+            .get(e)
+            .ok()
+            .map(|(e, shape, _)| SolverBody::new(e, shape))
+    });
+
+    solve_islands(&mut solver_islands, settings, cache, step.0);
+    cache.store(&solver_islands);
+
+    islands.awake.clear();
+    for island in &solver_islands {
+        for constraint in &island.joints {
+            if let Ok((_, mut joint)) = joints.get_mut(constraint.entity) {
+                joint.impulses = constraint.impulses;
+                if constraint.broken(step.0) {
+                    commands.entity(constraint.entity).despawn();
+                    broken.send(JointBroken {
+                        joint: constraint.entity,
+                        a: joint.a,
+                        b: joint.b,
+                    });
+                }
+            }
         }
-        //-------------------------------------------------------------------Friction-----------------------------------------------------------------------------------------
-        /* let mut impulsefrictionl = vec![];
-        for (j, i) in contactl.iter().enumerate() {
-            let j = jl[j];
-            let i = *i;
-            let ra = i - a.pos;
-            let rb = i - b.pos;
-            ral.push(ra);
-            rbl.push(rb);
-            let raper = Vec2::new(-ra.y, ra.x);
-            let rbper = Vec2::new(-rb.y, rb.x);
-
-            let alv_a = raper * a.rot_vel;
-            let alv_b = rbper * b.rot_vel;
-
-            let rel_vel = (b.vel + alv_b) - (a.vel + alv_a);
-
-            let mut tangent = rel_vel - rel_vel.dot(normal) * normal;
-
-            if tangent == Vec2::ZERO {
+        let mut awake = vec![];
+        for body in &island.bodies {
+            if !body.body.is_dynamic() {
                 continue;
-            } else {
-                tangent = tangent.normalize()
             }
-
-            let raperdot = raper.dot(tangent);
-            let rbperdot = rbper.dot(tangent);
-
-            let denom = a.inv_mass
-                + b.inv_mass
-                + (raperdot * raperdot) * a.inv_inertia
-                + (rbperdot * rbperdot) * b.inv_inertia;
-
-            let jt = -rel_vel.dot(tangent);
-            let jt = jt / denom;
-            let jt = jt / cc as f32;
-
-            let impulsefriction = if jt.abs() <= j * sf {
-                jt * tangent
-            } else {
-                -j * tangent * df
-            };
-            impulsefrictionl.push(impulsefriction);
+            if let Ok((_, mut shape, _)) = query.get_mut(body.entity) {
+                body.write(&mut shape);
+            }
+            awake.push(body.entity);
         }
-        for impulse in impulsefrictionl {
-
-            a.vel += -impulse * mainv;
-            b.vel += impulse * mbinv;
-        } */
-        
+        islands.awake.push(awake);
     }
 }
 
@@ -332,8 +539,7 @@ fn setup(
         Bodys::Rec(r, r),
         Vec2::new(0., 0.),
         Color::GREEN,
-        true,
-        false,
+        RigidBody::Kinematic,
     );
     create_body(
         &mut commands,
@@ -342,16 +548,204 @@ fn setup(
         Bodys::Rec(1000., 100.),
         Vec2::new(500., 50.),
         Color::DARK_GREEN,
-        false,
-        true,
+        RigidBody::Static,
     );
     let (mut red, mesh) = create_shape(
         &mut meshes,
         Bodys::Rec(500., 50.),
         Vec2::new(50., 500.),
-        false,
-        true,
+        RigidBody::Static,
     );
     red.rot = f32::to_radians(-20.);
     spawn_shape(&mut commands, Color::RED, mesh, &mut materials, red);
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::joints::{DistanceJoint, JointKind};
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::gizmos::{config::GizmoConfigStore, AppGizmoBuilder};
+
+    // Simulation without the window and the demo.
+    pub(crate) fn test_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.init_resource::<Assets<Mesh>>();
+        app.init_resource::<GizmoConfigStore>();
+        app.init_gizmo_group::<DefaultGizmoConfigGroup>();
+        add_simulation(&mut app);
+        app
+    }
+
+    pub(crate) fn spawn(app: &mut App, kind: Bodys, pos: Vec2, body: RigidBody) -> Entity {
+        app.world.run_system_once(
+            move |mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+                let (shape, _) = create_shape(&mut meshes, kind, pos, body);
+                commands.spawn(shape).id()
+            },
+        )
+    }
+
+    pub(crate) fn ground(app: &mut App) -> Entity {
+        let pos = Vec2::new(0., -50.);
+        spawn(app, Bodys::Rec(1000., 100.), pos, RigidBody::Static)
+    }
+
+    // Drops a pile of mixed shapes on the ground and records the checksum of
+    // every tick.
+    fn pile_checksums(ticks: usize) -> Vec<u64> {
+        let mut app = test_app();
+        app.world.resource_mut::<Determinism>().enabled = true;
+        app.world.resource_mut::<Substeps>().0 = 2;
+        ground(&mut app);
+        for i in 0..30 {
+            let kind = match i % 3 {
+                0 => Bodys::Circle(12.),
+                1 => Bodys::Rec(25., 18.),
+                _ => Bodys::Poly(14., 5),
+            };
+            let pos = Vec2::new(((i * 37) % 200) as f32 - 100., 40. + 30. * i as f32);
+            spawn(&mut app, kind, pos, RigidBody::Dynamic);
+        }
+
+        (0..ticks)
+            .map(|_| {
+                app.world.run_schedule(FixedUpdate);
+                app.world.resource::<WorldChecksum>().value
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_scene_gives_same_checksums() {
+        let first = pile_checksums(200);
+        let second = pile_checksums(200);
+        assert_ne!(first[0], first[199]);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn box_stack_stays_upright() {
+        let mut app = test_app();
+        ground(&mut app);
+        let boxes: Vec<_> = (0..5)
+            .map(|i| {
+                let pos = Vec2::new(0., 10. + 20. * i as f32);
+                spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Dynamic)
+            })
+            .collect();
+
+        for _ in 0..400 {
+            app.world.run_schedule(FixedUpdate);
+        }
+        for (i, entity) in boxes.into_iter().enumerate() {
+            let shape = app.world.get::<Shape>(entity).unwrap();
+            assert!(shape.pos.x.abs() < 1., "box {i} slid to {}", shape.pos);
+            assert!((shape.pos.y - (10. + 20. * i as f32)).abs() < 3.);
+            assert!(shape.rot.abs() < 0.05, "box {i} turned to {}", shape.rot);
+        }
+    }
+
+    #[test]
+    fn changing_body_type_updates_the_inverse_mass() {
+        let mut app = test_app();
+        ground(&mut app);
+        let pos = Vec2::new(0., 10.);
+        let lifted = spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Static);
+        let pos = Vec2::new(100., 10.);
+        let settled = spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Dynamic);
+        for _ in 0..200 {
+            app.world.run_schedule(FixedUpdate);
+        }
+        assert!(app.world.get::<Sleeping>(settled).is_some());
+
+        let mut shape = app.world.get_mut::<Shape>(lifted).unwrap();
+        shape.set_body(RigidBody::Dynamic);
+        assert!(shape.inv_mass > 0.);
+        let mut shape = app.world.get_mut::<Shape>(settled).unwrap();
+        shape.set_body(RigidBody::Static);
+        assert_eq!(shape.inv_mass, 0.);
+        for _ in 0..200 {
+            app.world.run_schedule(FixedUpdate);
+        }
+
+        let shape = app.world.get::<Shape>(lifted).unwrap();
+        assert!((shape.pos.y - 10.).abs() < 1., "fell to {}", shape.pos);
+        assert!(app.world.get::<Sleeping>(settled).is_none());
+    }
+
+    #[test]
+    fn zero_time_scale_keeps_joints_finite() {
+        let mut app = test_app();
+        let pos = Vec2::new(100., 300.);
+        let bob = spawn(&mut app, Bodys::Circle(10.), pos, RigidBody::Dynamic);
+        let kind = JointKind::Distance(DistanceJoint::new(100.));
+        let anchor = Vec2::new(0., 300.);
+        let joint = Joint::new(kind, bob, None, Vec2::ZERO, anchor);
+        app.world.spawn(joint);
+        app.world.resource_mut::<PhysicsTime>().time_scale = 0.;
+        for _ in 0..10 {
+            app.world.run_schedule(FixedUpdate);
+        }
+
+        let shape = app.world.get::<Shape>(bob).unwrap();
+        assert_eq!(shape.pos, pos);
+        assert!(shape.vel.is_finite());
+    }
+
+    #[test]
+    fn step_only_counts_while_paused() {
+        let mut physics_time = PhysicsTime::default();
+        physics_time.step();
+        assert!(physics_time.consume_tick());
+        physics_time.pause();
+        assert!(!physics_time.consume_tick());
+        physics_time.step();
+        assert!(physics_time.consume_tick());
+        assert!(!physics_time.consume_tick());
+    }
+
+    #[test]
+    fn zero_timestep_skips_the_tick() {
+        let mut app = test_app();
+        let pos = Vec2::new(0., 300.);
+        let body = spawn(&mut app, Bodys::Circle(10.), pos, RigidBody::Dynamic);
+        app.world.resource_mut::<PhysicsTime>().timestep = Duration::ZERO;
+        app.world.run_schedule(FixedUpdate);
+
+        let shape = app.world.get::<Shape>(body).unwrap();
+        assert_eq!(shape.pos, pos);
+        assert_eq!(app.world.resource::<WorldChecksum>().step, 0);
+    }
+
+    #[test]
+    fn removing_a_support_wakes_the_bodies_on_it() {
+        let mut app = test_app();
+        let floor = ground(&mut app);
+        let pos = Vec2::new(0., 10.);
+        let resting = spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Dynamic);
+        let pos = Vec2::new(300., 300.);
+        let hook = spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Static);
+        let pos = Vec2::new(300., 200.);
+        let hanging = spawn(&mut app, Bodys::Circle(10.), pos, RigidBody::Dynamic);
+        let kind = JointKind::Distance(DistanceJoint::new(100.));
+        let joint = Joint::new(kind, hanging, Some(hook), Vec2::ZERO, Vec2::ZERO);
+        app.world.spawn(joint);
+        for _ in 0..200 {
+            app.world.run_schedule(FixedUpdate);
+        }
+        assert!(app.world.get::<Sleeping>(resting).is_some());
+        assert!(app.world.get::<Sleeping>(hanging).is_some());
+
+        app.world.despawn(floor);
+        app.world.despawn(hook);
+        for _ in 0..20 {
+            app.world.run_schedule(FixedUpdate);
+        }
+        for body in [resting, hanging] {
+            assert!(app.world.get::<Sleeping>(body).is_none());
+            assert!(app.world.get::<Shape>(body).unwrap().vel.y < 0.);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use bevy::prelude::*;
+
+use super::body::Shape;
+use super::determinism::WorldChecksum;
+use super::islands::{Islands, Sleeping};
+use super::joints::Joint;
+use super::solver::ContactCache;
+
+// Everything that changes while simulating a single body.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyState {
+    pub pos: Vec2,
+    pub rot: f32,
+    pub vel: Vec2,
+    pub rot_vel: f32,
+    pub force: Vec2,
+    pub torque: f32,
+    pub sleep_time: f32,
+    pub sleeping: bool,
+}
+
+// Copy of the physics state of the world, used for rollback. Bodies spawned
+// after the capture are left alone on restore and despawned ones are not
+// brought back. Joints are kept whole, one broken since the capture is spawned
+// again on its entity unless that entity was reused in the meantime.
+#[derive(Clone)]
+pub struct PhysicsSnapshot {
+    pub bodies: Vec<(Entity, BodyState)>,
+    // Joints with the impulses they keep to warm start the solver.
+    pub joints: Vec<(Entity, Joint)>,
+    contacts: ContactCache,
+    islands: Islands,
+    checksum: (u64, u64),
+}
+
+impl PhysicsSnapshot {
+    pub fn capture(world: &mut World) -> PhysicsSnapshot {
+        let mut query = world.query::<(Entity, &Shape, Has<Sleeping>)>();
+        let mut bodies: Vec<_> = query
+            .iter(world)
+            .map(|(entity, shape, sleeping)| {
+                let state = BodyState {
+                    pos: shape.pos,
+                    rot: shape.rot,
+                    vel: shape.vel,
+                    rot_vel: shape.rot_vel,
+                    force: shape.force,
+                    torque: shape.torque,
+                    sleep_time: shape.sleep_time,
+                    sleeping,
+                };
+                (entity, state)
+            })
+            .collect();
+        bodies.sort_by_key(|(entity, _)| *entity);
+        let mut joints: Vec<_> = world
+            .query::<(Entity, &Joint)>()
+            .iter(world)
+            .map(|(entity, joint)| (entity, joint.clone()))
+            .collect();
+        joints.sort_by_key(|(entity, _)| *entity);
+
+        let checksum = world
+            .get_resource::<WorldChecksum>()
+            .map_or((0, 0), |checksum| (checksum.step, checksum.value));
+        PhysicsSnapshot {
+            bodies,
+            joints,
+            contacts: world.resource::<ContactCache>().clone(),
+            islands: world.resource::<Islands>().clone(),
+            checksum,
+        }
+    }
+
+    pub fn restore(&self, world: &mut World) {
+        for (entity, state) in &self.bodies {
+            let Some(mut body) = world.get_entity_mut(*entity) else {
+                continue;
+            };
+            if let Some(mut shape) = body.get_mut::<Shape>() {
+                shape.pos = state.pos;
+                shape.rot = state.rot;
+                shape.vel = state.vel;
+                shape.rot_vel = state.rot_vel;
+                shape.force = state.force;
+                shape.torque = state.torque;
+                shape.sleep_time = state.sleep_time;
+                shape.update_pose();
+            }
+            if state.sleeping {
+                body.insert(Sleeping);
+            } else {
+                body.remove::<Sleeping>();
+            }
+        }
+
+        for (entity, joint) in &self.joints {
+            match world.get_or_spawn(*entity) {
+                Some(mut entity) => {
+                    entity.insert(joint.clone());
+                }
+                None => warn!("joint {entity:?} can't be restored, its entity was reused"),
+            }
+        }
+
+        *world.resource_mut::<ContactCache>() = self.contacts.clone();
+        *world.resource_mut::<Islands>() = self.islands.clone();
+        if let Some(mut checksum) = world.get_resource_mut::<WorldChecksum>() {
+            (checksum.step, checksum.value) = self.checksum;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::{Bodys, RigidBody};
+    use crate::determinism::Determinism;
+    use crate::joints::{JointKind, WeldJoint};
+    use crate::physics::tests::{ground, spawn, test_app};
+
+    fn checksums(app: &mut App, ticks: usize) -> Vec<u64> {
+        (0..ticks)
+            .map(|_| {
+                app.world.run_schedule(FixedUpdate);
+                app.world.resource::<WorldChecksum>().value
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restore_replays_the_same_steps() {
+        let mut app = test_app();
+        app.world.resource_mut::<Determinism>().enabled = true;
+        ground(&mut app);
+        for i in 0..10 {
+            let kind = match i % 2 {
+                0 => Bodys::Circle(12.),
+                _ => Bodys::Rec(25., 18.),
+            };
+            let pos = Vec2::new(((i * 37) % 200) as f32 - 100., 40. + 30. * i as f32);
+            spawn(&mut app, kind, pos, RigidBody::Dynamic);
+        }
+        // The ball lands after the capture and breaks the weld, the restore has
+        // to bring it back.
+        let pos = Vec2::new(300., 300.);
+        let block = spawn(&mut app, Bodys::Rec(20., 20.), pos, RigidBody::Dynamic);
+        let ball = Vec2::new(300., 340.);
+        spawn(&mut app, Bodys::Circle(12.), ball, RigidBody::Dynamic);
+        let mut weld = WeldJoint::new();
+        weld.break_force = app.world.get::<Shape>(block).unwrap().mass * 392.;
+        let kind = JointKind::Weld(weld);
+        let joint = app
+            .world
+            .spawn(Joint::new(kind, block, None, Vec2::ZERO, pos))
+            .id();
+        checksums(&mut app, 20);
+        assert!(app.world.get_entity(joint).is_some());
+
+        let snapshot = PhysicsSnapshot::capture(&mut app.world);
+        let first = checksums(&mut app, 100);
+        assert!(app.world.get_entity(joint).is_none());
+        snapshot.restore(&mut app.world);
+        assert!(app.world.get::<Joint>(joint).is_some());
+        let second = checksums(&mut app, 100);
+        assert_eq!(first, second);
+    }
+}
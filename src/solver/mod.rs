@@ -0,0 +1,565 @@
+use bevy::{prelude::*, tasks::ComputeTaskPool, utils::HashMap};
+
+use super::body::{RigidBody, Shape};
+use super::collisions::Contact;
+use super::joints::{JointConstraint, JointImpulses};
+use super::xpbd;
+
+#[derive(Clone, Copy)]
+pub struct SolverBody {
+    pub entity: Entity,
+    // World position of the center of mass.
+    pub pos: Vec2,
+    pub rot: f32,
+    pub vel: Vec2,
+    pub rot_vel: f32,
+    // Velocities only used to push bodies out of each other, never kept.
+    pub pseudo_vel: Vec2,
+    pub pseudo_rot_vel: f32,
+    // Zero along locked axes, so impulses never move the body along them.
+    pub inv_mass: Vec2,
+    pub inv_inertia: f32,
+    pub restitution: f32,
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+    pub body: RigidBody,
+}
+
+impl SolverBody {
+    pub fn new(entity: Entity, shape: &Shape) -> SolverBody {
+        SolverBody {
+            entity,
+            pos: shape.world_center_of_mass(),
+            rot: shape.rot,
+            vel: shape.vel,
+            rot_vel: shape.rot_vel,
+            pseudo_vel: Vec2::ZERO,
+            pseudo_rot_vel: 0.,
+            inv_mass: shape.effective_inv_mass(),
+            inv_inertia: shape.effective_inv_inertia(),
+            restitution: shape.restitution,
+            static_friction: shape.static_friction,
+            dynamic_friction: shape.dinaminc_friction,
+            body: shape.body,
+        }
+    }
+
+    // Static body standing for the world in joints pinned to it.
+    pub fn world() -> SolverBody {
+        SolverBody {
+            entity: Entity::PLACEHOLDER,
+            pos: Vec2::ZERO,
+            rot: 0.,
+            vel: Vec2::ZERO,
+            rot_vel: 0.,
+            pseudo_vel: Vec2::ZERO,
+            pseudo_rot_vel: 0.,
+            inv_mass: Vec2::ZERO,
+            inv_inertia: 0.,
+            restitution: 0.,
+            static_friction: 0.,
+            dynamic_friction: 0.,
+            body: RigidBody::Static,
+        }
+    }
+
+    pub fn write(&self, shape: &mut Shape) {
+        shape.rot = self.rot;
+        shape.place_center_of_mass(self.pos);
+        shape.vel = self.vel;
+        shape.rot_vel = self.rot_vel;
+        shape.limit_velocity();
+        shape.update_pose();
+    }
+}
+
+// Group of bodies that touch each other or are jointed through dynamic bodies.
+// Static and kinematic bodies never link islands together, so they may be
+// copied into several of them.
+pub struct Island {
+    pub bodies: Vec<SolverBody>,
+    pub contacts: Vec<(usize, usize, Contact)>,
+    pub constraints: Vec<ContactConstraint>,
+    pub joints: Vec<JointConstraint>,
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+pub fn build_islands(
+    contacts: Vec<Contact>,
+    joints: Vec<JointConstraint>,
+    mut body: impl FnMut(Entity) -> Option<SolverBody>,
+) -> Vec<Island> {
+    let mut bodies: Vec<SolverBody> = vec![];
+    let mut index: HashMap<Entity, usize> = HashMap::default();
+    let mut resolve = |entity: Entity| {
+        if let Some(&i) = index.get(&entity) {
+            return Some(i);
+        }
+        let solver_body = if entity == Entity::PLACEHOLDER {
+            SolverBody::world()
+        } else {
+            body(entity)?
+        };
+        index.insert(entity, bodies.len());
+        bodies.push(solver_body);
+        Some(bodies.len() - 1)
+    };
+
+    let mut contact_bodies = vec![];
+    for contact in contacts {
+        if let (Some(a), Some(b)) = (resolve(contact.a), resolve(contact.b)) {
+            contact_bodies.push((a, b, contact));
+        }
+    }
+    let mut joint_bodies = vec![];
+    for joint in joints {
+        if let (Some(a), Some(b)) = (resolve(joint.body_a), resolve(joint.body_b)) {
+            joint_bodies.push((a, b, joint));
+        }
+    }
+
+    let mut parents: Vec<usize> = (0..bodies.len()).collect();
+    let links = contact_bodies
+        .iter()
+        .map(|(a, b, _)| (*a, *b))
+        .chain(joint_bodies.iter().map(|(a, b, _)| (*a, *b)));
+    for (a, b) in links {
+        if bodies[a].body.is_dynamic() && bodies[b].body.is_dynamic() {
+            let ra = find(&mut parents, a);
+            let rb = find(&mut parents, b);
+            parents[ra] = rb;
+        }
+    }
+
+    let mut islands: Vec<Island> = vec![];
+    let mut island_of_root: HashMap<usize, usize> = HashMap::default();
+    let mut local: Vec<HashMap<usize, usize>> = vec![];
+    let mut place = |a: usize, b: usize| {
+        let dynamic = if bodies[a].body.is_dynamic() { a } else { b };
+        let root = find(&mut parents, dynamic);
+        let island = *island_of_root.entry(root).or_insert_with(|| {
+            islands.push(Island {
+                bodies: vec![],
+                contacts: vec![],
+                constraints: vec![],
+                joints: vec![],
+            });
+            local.push(HashMap::default());
+            islands.len() - 1
+        });
+        let mut local_id = |i: usize| {
+            *local[island].entry(i).or_insert_with(|| {
+                islands[island].bodies.push(bodies[i]);
+                islands[island].bodies.len() - 1
+            })
+        };
+        let (la, lb) = (local_id(a), local_id(b));
+        (island, la, lb)
+    };
+
+    let mut placed_contacts = vec![];
+    for (a, b, contact) in contact_bodies {
+        placed_contacts.push((place(a, b), contact));
+    }
+    let mut placed_joints = vec![];
+    for (a, b, mut joint) in joint_bodies {
+        if !bodies[a].body.is_dynamic() && !bodies[b].body.is_dynamic() {
+            continue;
+        }
+        let (island, la, lb) = place(a, b);
+        (joint.a, joint.b) = (la, lb);
+        placed_joints.push((island, joint));
+    }
+    for ((island, la, lb), contact) in placed_contacts {
+        islands[island].contacts.push((la, lb, contact));
+    }
+    for (island, joint) in placed_joints {
+        islands[island].joints.push(joint);
+    }
+    islands
+}
+
+pub fn pair_mut(
+    bodies: &mut [SolverBody],
+    a: usize,
+    b: usize,
+) -> (&mut SolverBody, &mut SolverBody) {
+    debug_assert_ne!(a, b, "constraint between a body and itself");
+    if a < b {
+        let (left, right) = bodies.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = bodies.split_at_mut(a);
+        (&mut right[0], &mut left[b])
+    }
+}
+
+fn perp(v: Vec2) -> Vec2 {
+    Vec2::new(-v.y, v.x)
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ContactPoint {
+    pub point: Vec2,
+    pub ra: Vec2,
+    pub rb: Vec2,
+    pub normal_mass: f32,
+    pub tangent_mass: f32,
+    pub normal_impulse: f32,
+    pub tangent_impulse: f32,
+    pub velocity_bias: f32,
+    pub position_impulse: f32,
+    pub position_bias: f32,
+}
+
+pub struct ContactConstraint {
+    pub a: usize,
+    pub b: usize,
+    pub normal: Vec2,
+    pub depth: f32,
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+    pub points: Vec<ContactPoint>,
+}
+
+impl ContactConstraint {
+    fn new(
+        bodies: &[SolverBody],
+        ia: usize,
+        ib: usize,
+        contact: &Contact,
+        settings: &SolverSettings,
+        time: f32,
+    ) -> Self {
+        let (a, b) = (&bodies[ia], &bodies[ib]);
+        let normal = contact.normal;
+        let tangent = -perp(normal);
+        let e = f32::min(a.restitution, b.restitution);
+        let points = contact
+            .points()
+            .iter()
+            .zip(contact.depths)
+            .map(|(i, depth)| {
+                let ra = *i - a.pos;
+                let rb = *i - b.pos;
+
+                let mass = |axis: Vec2| {
+                    let raperdot = perp(ra).dot(axis);
+                    let rbperdot = perp(rb).dot(axis);
+                    let denom = (axis * axis).dot(a.inv_mass + b.inv_mass)
+                        + (raperdot * raperdot) * a.inv_inertia
+                        + (rbperdot * rbperdot) * b.inv_inertia;
+                    if denom > 0. {
+                        1. / denom
+                    } else {
+                        0.
+                    }
+                };
+
+                let rel_vel = (b.vel + perp(rb) * b.rot_vel) - (a.vel + perp(ra) * a.rot_vel);
+                let contacvel = rel_vel.dot(normal);
+                let restitution = if contacvel < -RESTITUTION_THRESHOLD {
+                    -e * contacvel
+                } else {
+                    0.
+                };
+
+                // Separating speed that removes the penetration beyond `slop` over a few steps.
+                let correction = if time > 0. {
+                    settings.correction_factor * (depth - settings.slop).max(0.) / time
+                } else {
+                    0.
+                };
+                let (baumgarte, position_bias) = match settings.position_correction {
+                    PositionCorrection::Baumgarte => (correction, 0.),
+                    PositionCorrection::SplitImpulse => (0., correction),
+                };
+
+                ContactPoint {
+                    point: *i,
+                    ra,
+                    rb,
+                    normal_mass: mass(normal),
+                    tangent_mass: mass(tangent),
+                    velocity_bias: restitution.max(baumgarte),
+                    position_bias,
+                    ..default()
+                }
+            })
+            .collect();
+
+        ContactConstraint {
+            a: ia,
+            b: ib,
+            normal,
+            depth: contact.depth,
+            static_friction: (a.static_friction + b.static_friction) * 0.5,
+            dynamic_friction: (a.dynamic_friction + b.dynamic_friction) * 0.5,
+            points,
+        }
+    }
+
+    fn apply(a: &mut SolverBody, b: &mut SolverBody, cp: &ContactPoint, impulse: Vec2) {
+        a.vel += -impulse * a.inv_mass;
+        a.rot_vel += -cp.ra.perp_dot(impulse) * a.inv_inertia;
+        b.vel += impulse * b.inv_mass;
+        b.rot_vel += cp.rb.perp_dot(impulse) * b.inv_inertia;
+    }
+
+    fn apply_pseudo(a: &mut SolverBody, b: &mut SolverBody, cp: &ContactPoint, impulse: Vec2) {
+        a.pseudo_vel += -impulse * a.inv_mass;
+        a.pseudo_rot_vel += -cp.ra.perp_dot(impulse) * a.inv_inertia;
+        b.pseudo_vel += impulse * b.inv_mass;
+        b.pseudo_rot_vel += cp.rb.perp_dot(impulse) * b.inv_inertia;
+    }
+
+    fn warm_start(&self, bodies: &mut [SolverBody]) {
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let tangent = -perp(self.normal);
+        for cp in &self.points {
+            let impulse = self.normal * cp.normal_impulse + tangent * cp.tangent_impulse;
+            ContactConstraint::apply(a, b, cp, impulse);
+        }
+    }
+
+    fn solve_velocity(&mut self, bodies: &mut [SolverBody]) {
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let normal = self.normal;
+        let tangent = -perp(normal);
+
+        for cp in &mut self.points {
+            let rel_vel = (b.vel + perp(cp.rb) * b.rot_vel) - (a.vel + perp(cp.ra) * a.rot_vel);
+            let jt = -rel_vel.dot(tangent) * cp.tangent_mass;
+
+            let old = cp.tangent_impulse;
+            let max = cp.normal_impulse * self.static_friction;
+            cp.tangent_impulse = if (old + jt).abs() <= max {
+                old + jt
+            } else {
+                let max = cp.normal_impulse * self.dynamic_friction;
+                (old + jt).clamp(-max, max)
+            };
+            ContactConstraint::apply(a, b, cp, tangent * (cp.tangent_impulse - old));
+        }
+
+        for cp in &mut self.points {
+            let rel_vel = (b.vel + perp(cp.rb) * b.rot_vel) - (a.vel + perp(cp.ra) * a.rot_vel);
+            let contacvel = rel_vel.dot(normal);
+            let j = -(contacvel - cp.velocity_bias) * cp.normal_mass;
+
+            let old = cp.normal_impulse;
+            cp.normal_impulse = (old + j).max(0.);
+            ContactConstraint::apply(a, b, cp, normal * (cp.normal_impulse - old));
+        }
+    }
+
+    fn solve_position(&mut self, bodies: &mut [SolverBody]) {
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let normal = self.normal;
+
+        for cp in &mut self.points {
+            let rel_vel = (b.pseudo_vel + perp(cp.rb) * b.pseudo_rot_vel)
+                - (a.pseudo_vel + perp(cp.ra) * a.pseudo_rot_vel);
+            let j = -(rel_vel.dot(normal) - cp.position_bias) * cp.normal_mass;
+
+            let old = cp.position_impulse;
+            cp.position_impulse = (old + j).max(0.);
+            ContactConstraint::apply_pseudo(a, b, cp, normal * (cp.position_impulse - old));
+        }
+    }
+}
+
+pub const RESTITUTION_THRESHOLD: f32 = 30.;
+const WARM_START_DISTANCE: f32 = 4.;
+
+// How penetration left after the velocity solve is removed. Baumgarte adds a
+// bias to the contact velocity, which may add energy. Split impulse solves the
+// bias on separate pseudo velocities that only move the bodies.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum PositionCorrection {
+    Baumgarte,
+    #[default]
+    SplitImpulse,
+}
+
+// Sequential impulses with warm starting, or extended position based dynamics
+// where contacts are compliant position constraints.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SolverBackend {
+    #[default]
+    Impulse,
+    Xpbd,
+}
+
+#[derive(Resource)]
+pub struct SolverSettings {
+    pub backend: SolverBackend,
+    pub velocity_iterations: usize,
+    pub position_iterations: usize,
+    pub warm_starting: bool,
+    pub position_correction: PositionCorrection,
+    // Penetration depth tolerated without correction, avoids jitter on resting contacts.
+    pub slop: f32,
+    // Fraction of the penetration removed every step.
+    pub correction_factor: f32,
+    // Inverse stiffness of the contacts solved by the XPBD backend, 0 is rigid.
+    pub contact_compliance: f32,
+}
+
+impl std::default::Default for SolverSettings {
+    fn default() -> Self {
+        Self {
+            backend: SolverBackend::Impulse,
+            velocity_iterations: 8,
+            position_iterations: 3,
+            warm_starting: true,
+            position_correction: PositionCorrection::SplitImpulse,
+            slop: 0.5,
+            correction_factor: 0.2,
+            contact_compliance: 0.,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CachedContact {
+    pub point: Vec2,
+    pub normal_impulse: f32,
+    pub tangent_impulse: f32,
+}
+
+// Accumulated impulses of the last step, used to warm start matching contacts.
+#[derive(Resource, Default, Clone)]
+pub struct ContactCache {
+    pub contacts: HashMap<(Entity, Entity), Vec<CachedContact>>,
+}
+
+impl ContactCache {
+    fn key(a: Entity, b: Entity) -> (Entity, Entity) {
+        if a < b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    fn load(&self, a: Entity, b: Entity, constraint: &mut ContactConstraint) {
+        let Some(cached) = self.contacts.get(&ContactCache::key(a, b)) else {
+            return;
+        };
+        for cp in &mut constraint.points {
+            let old = cached
+                .iter()
+                .find(|old| old.point.distance(cp.point) < WARM_START_DISTANCE);
+            if let Some(old) = old {
+                cp.normal_impulse = old.normal_impulse;
+                cp.tangent_impulse = old.tangent_impulse;
+            }
+        }
+    }
+
+    pub fn store(&mut self, islands: &[Island]) {
+        self.contacts.clear();
+        for island in islands {
+            for constraint in &island.constraints {
+                let a = island.bodies[constraint.a].entity;
+                let b = island.bodies[constraint.b].entity;
+                let points = constraint
+                    .points
+                    .iter()
+                    .map(|cp| CachedContact {
+                        point: cp.point,
+                        normal_impulse: cp.normal_impulse,
+                        tangent_impulse: cp.tangent_impulse,
+                    })
+                    .collect();
+                self.contacts.insert(ContactCache::key(a, b), points);
+            }
+        }
+    }
+}
+
+fn solve_positions(island: &mut Island, iterations: usize, time: f32) {
+    for _ in 0..iterations {
+        for constraint in &mut island.constraints {
+            constraint.solve_position(&mut island.bodies);
+        }
+    }
+    for body in &mut island.bodies {
+        body.pos += body.pseudo_vel * time;
+        body.rot += body.pseudo_rot_vel * time;
+    }
+}
+
+pub fn solve_island(
+    island: &mut Island,
+    settings: &SolverSettings,
+    cache: &ContactCache,
+    time: f32,
+) {
+    island.constraints = island
+        .contacts
+        .iter()
+        .map(|(ia, ib, contact)| {
+            let mut constraint =
+                ContactConstraint::new(&island.bodies, *ia, *ib, contact, settings, time);
+            if settings.warm_starting {
+                cache.load(contact.a, contact.b, &mut constraint);
+            }
+            constraint
+        })
+        .collect();
+
+    for joint in &mut island.joints {
+        if settings.warm_starting {
+            joint.warm_start(&mut island.bodies);
+        } else {
+            joint.impulses = JointImpulses::default();
+        }
+    }
+    for constraint in &island.constraints {
+        constraint.warm_start(&mut island.bodies);
+    }
+    for _ in 0..settings.velocity_iterations {
+        for joint in &mut island.joints {
+            joint.solve_velocity(&mut island.bodies, settings, time);
+        }
+        for constraint in &mut island.constraints {
+            constraint.solve_velocity(&mut island.bodies);
+        }
+    }
+
+    if settings.position_correction == PositionCorrection::SplitImpulse {
+        solve_positions(island, settings.position_iterations, time);
+    }
+}
+
+// Islands share no dynamic bodies, so each one is solved on its own task.
+pub fn solve_islands(
+    islands: &mut [Island],
+    settings: &SolverSettings,
+    cache: &ContactCache,
+    time: f32,
+) {
+    // Nothing moves during an empty step, and the joints divide by its length.
+    if time <= 0. {
+        return;
+    }
+    ComputeTaskPool::get().scope(|s| {
+        for island in islands.iter_mut() {
+            s.spawn(async move {
+                match settings.backend {
+                    SolverBackend::Impulse => solve_island(island, settings, cache, time),
+                    SolverBackend::Xpbd => xpbd::solve_island(island, settings, time),
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,261 @@
+use bevy::prelude::*;
+
+use super::collisions::Contact;
+use super::solver::{pair_mut, Island, SolverBody, SolverSettings, RESTITUTION_THRESHOLD};
+
+// Extended position based dynamics. Constraints move the bodies directly and
+// the velocities are derived from how far they moved. Every constraint has a
+// compliance, the inverse of its stiffness, so 0 means fully rigid.
+
+// Inverse mass of a body seen from a point `r` away from its center along `n`.
+pub fn generalized_inv_mass(body: &SolverBody, r: Vec2, n: Vec2) -> f32 {
+    (n * n).dot(body.inv_mass) + r.perp_dot(n).powi(2) * body.inv_inertia
+}
+
+// Change of the Lagrange multiplier that removes the error `c` of a constraint
+// with generalized inverse mass `w`.
+pub fn delta_lambda(c: f32, w: f32, lambda: f32, compliance: f32, time: f32) -> f32 {
+    let alpha = compliance / (time * time);
+    if w + alpha <= 0. {
+        return 0.;
+    }
+    (c - alpha * lambda) / (w + alpha)
+}
+
+// Moves `b` along `impulse` and `a` the other way, at the points `ra` and `rb`.
+pub fn apply_position_impulse(
+    a: &mut SolverBody,
+    b: &mut SolverBody,
+    ra: Vec2,
+    rb: Vec2,
+    impulse: Vec2,
+) {
+    a.pos -= impulse * a.inv_mass;
+    a.rot -= ra.perp_dot(impulse) * a.inv_inertia;
+    b.pos += impulse * b.inv_mass;
+    b.rot += rb.perp_dot(impulse) * b.inv_inertia;
+}
+
+pub fn apply_velocity_impulse(
+    a: &mut SolverBody,
+    b: &mut SolverBody,
+    ra: Vec2,
+    rb: Vec2,
+    impulse: Vec2,
+) {
+    a.vel -= impulse * a.inv_mass;
+    a.rot_vel -= ra.perp_dot(impulse) * a.inv_inertia;
+    b.vel += impulse * b.inv_mass;
+    b.rot_vel += rb.perp_dot(impulse) * b.inv_inertia;
+}
+
+// Turns `b` by `impulse` times its inverse inertia and `a` the other way.
+pub fn apply_angular_position_impulse(a: &mut SolverBody, b: &mut SolverBody, impulse: f32) {
+    a.rot -= impulse * a.inv_inertia;
+    b.rot += impulse * b.inv_inertia;
+}
+
+pub fn apply_angular_velocity_impulse(a: &mut SolverBody, b: &mut SolverBody, impulse: f32) {
+    a.rot_vel -= impulse * a.inv_inertia;
+    b.rot_vel += impulse * b.inv_inertia;
+}
+
+// Offset `r` taken when the body had the rotation of `start`, turned to its current rotation.
+pub fn rotated(body: &SolverBody, start: &SolverBody, r: Vec2) -> Vec2 {
+    Vec2::from_angle(body.rot - start.rot).rotate(r)
+}
+
+// Velocity of the point `r` away from the center of `body`.
+pub fn point_velocity(body: &SolverBody, r: Vec2) -> Vec2 {
+    body.vel + r.perp() * body.rot_vel
+}
+
+struct ContactPoint {
+    // Offsets of the contact point from both centers at the start of the step.
+    ra: Vec2,
+    rb: Vec2,
+    depth: f32,
+    normal_lambda: f32,
+    tangent_lambda: f32,
+    // Relative normal velocity before solving, used for restitution.
+    normal_velocity: f32,
+}
+
+struct XpbdContact {
+    a: usize,
+    b: usize,
+    normal: Vec2,
+    restitution: f32,
+    static_friction: f32,
+    dynamic_friction: f32,
+    points: Vec<ContactPoint>,
+}
+
+impl XpbdContact {
+    fn new(bodies: &[SolverBody], ia: usize, ib: usize, contact: &Contact) -> Self {
+        let (a, b) = (&bodies[ia], &bodies[ib]);
+        let points = contact
+            .points()
+            .iter()
+            .zip(contact.depths)
+            .map(|(point, depth)| {
+                let (ra, rb) = (*point - a.pos, *point - b.pos);
+                ContactPoint {
+                    ra,
+                    rb,
+                    depth,
+                    normal_lambda: 0.,
+                    tangent_lambda: 0.,
+                    normal_velocity: (point_velocity(b, rb) - point_velocity(a, ra))
+                        .dot(contact.normal),
+                }
+            })
+            .collect();
+
+        XpbdContact {
+            a: ia,
+            b: ib,
+            normal: contact.normal,
+            restitution: f32::min(a.restitution, b.restitution),
+            static_friction: (a.static_friction + b.static_friction) * 0.5,
+            dynamic_friction: (a.dynamic_friction + b.dynamic_friction) * 0.5,
+            points,
+        }
+    }
+
+    fn solve_position(
+        &mut self,
+        bodies: &mut [SolverBody],
+        start: &[SolverBody],
+        compliance: f32,
+        time: f32,
+    ) {
+        let (sa, sb) = (&start[self.a], &start[self.b]);
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let normal = self.normal;
+
+        for cp in &mut self.points {
+            // Both offsets pointed at the same spot when the contact was found.
+            let (ra, rb) = (rotated(a, sa, cp.ra), rotated(b, sb, cp.rb));
+            let separation = ((b.pos + rb) - (a.pos + ra)).dot(normal);
+            let c = cp.depth - separation;
+            if c <= 0. {
+                continue;
+            }
+            let w = generalized_inv_mass(a, ra, normal) + generalized_inv_mass(b, rb, normal);
+            let dl = delta_lambda(c, w, cp.normal_lambda, compliance, time).max(-cp.normal_lambda);
+            cp.normal_lambda += dl;
+            apply_position_impulse(a, b, ra, rb, normal * dl);
+
+            // Static friction, undo the sliding of the points during the step.
+            let (ra, rb) = (rotated(a, sa, cp.ra), rotated(b, sb, cp.rb));
+            let prev = |s: &SolverBody, r: Vec2| {
+                s.pos - s.vel * time + Vec2::from_angle(-s.rot_vel * time).rotate(r)
+            };
+            let moved = ((b.pos + rb) - prev(sb, cp.rb)) - ((a.pos + ra) - prev(sa, cp.ra));
+            let sliding = moved - normal * moved.dot(normal);
+            let c = sliding.length();
+            if c <= f32::EPSILON {
+                continue;
+            }
+            let direction = -sliding / c;
+            let w = generalized_inv_mass(a, ra, direction) + generalized_inv_mass(b, rb, direction);
+            let dl = delta_lambda(c, w, cp.tangent_lambda, 0., time);
+            if cp.tangent_lambda + dl < self.static_friction * cp.normal_lambda {
+                cp.tangent_lambda += dl;
+                apply_position_impulse(a, b, ra, rb, direction * dl);
+            }
+        }
+    }
+
+    // Dynamic friction is only applied on the first pass, the normal velocity is
+    // driven towards its restitution target on every pass.
+    fn solve_velocity(
+        &self,
+        bodies: &mut [SolverBody],
+        start: &[SolverBody],
+        time: f32,
+        friction: bool,
+    ) {
+        let (sa, sb) = (&start[self.a], &start[self.b]);
+        let (a, b) = pair_mut(bodies, self.a, self.b);
+        let normal = self.normal;
+
+        for cp in &self.points {
+            if cp.normal_lambda <= 0. {
+                continue;
+            }
+            let (ra, rb) = (rotated(a, sa, cp.ra), rotated(b, sb, cp.rb));
+            let rel_vel = point_velocity(b, rb) - point_velocity(a, ra);
+            let vn = rel_vel.dot(normal);
+            let vt = rel_vel - normal * vn;
+
+            let normal_force = cp.normal_lambda / (time * time);
+            let friction = if friction {
+                -vt.normalize_or_zero()
+                    * f32::min(time * self.dynamic_friction * normal_force, vt.length())
+            } else {
+                Vec2::ZERO
+            };
+            let e = if cp.normal_velocity < -RESTITUTION_THRESHOLD {
+                self.restitution
+            } else {
+                0.
+            };
+            let bounce = normal * (-vn + (-e * cp.normal_velocity).max(0.));
+
+            let dv = friction + bounce;
+            let length = dv.length();
+            if length <= f32::EPSILON {
+                continue;
+            }
+            let direction = dv / length;
+            let w = generalized_inv_mass(a, ra, direction) + generalized_inv_mass(b, rb, direction);
+            if w > 0. {
+                apply_velocity_impulse(a, b, ra, rb, dv / w);
+            }
+        }
+    }
+}
+
+pub fn solve_island(island: &mut Island, settings: &SolverSettings, time: f32) {
+    if time <= 0. {
+        return;
+    }
+    let start = island.bodies.clone();
+    let mut contacts: Vec<XpbdContact> = island
+        .contacts
+        .iter()
+        .map(|(ia, ib, contact)| XpbdContact::new(&island.bodies, *ia, *ib, contact))
+        .collect();
+
+    for _ in 0..settings.position_iterations.max(1) {
+        for joint in &mut island.joints {
+            joint.solve_position(&mut island.bodies, &start, time);
+        }
+        for contact in &mut contacts {
+            contact.solve_position(
+                &mut island.bodies,
+                &start,
+                settings.contact_compliance,
+                time,
+            );
+        }
+    }
+
+    for joint in &mut island.joints {
+        joint.store_lambdas(time);
+    }
+    for (body, start) in island.bodies.iter_mut().zip(&start) {
+        body.vel += (body.pos - start.pos) / time;
+        body.rot_vel += (body.rot - start.rot) / time;
+    }
+    for joint in &mut island.joints {
+        joint.damp(&mut island.bodies, &start, time);
+    }
+    for i in 0..settings.velocity_iterations.max(1) {
+        for contact in &contacts {
+            contact.solve_velocity(&mut island.bodies, &start, time, i == 0);
+        }
+    }
+}